@@ -0,0 +1,852 @@
+use std::hash::Hash;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, Receiver};
+
+use crate::discard::{Discard, DiscardCallback, DiscardReason, OnDiscard};
+use crate::emitted::{Emitted, Stamped};
+use crate::keyed::{KeyLimits, Keyed};
+use crate::pending::{Batch, BatchLimits, Latest, Merge, Pending, Queue};
+use crate::stats::Counters;
+use crate::worker::{run_worker, Paced, Schedule, WorkerConfig};
+use crate::{ClosePolicy, RateLimiterHandle};
+
+/// Capacity of the output channel when none is configured.
+pub const DEFAULT_OUTPUT_CAPACITY: usize = 100;
+
+/// How the worker decides when to emit a value.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum EmissionMode {
+    /// Emit the most recent value at most once per delay.
+    #[default]
+    Throttle,
+    /// Emit the most recent value once no new value has arrived for the delay.
+    /// Every new value restarts the wait.
+    Debounce,
+    /// Emit values as long as tokens are available, with one token added per
+    /// delay up to `burst`.
+    ///
+    /// After an idle period up to `burst` values go out straight away before
+    /// the channel settles into the sustained rate of one value per delay.
+    TokenBucket {
+        /// The most tokens the bucket can hold. Must be greater than zero.
+        burst: u32,
+    },
+}
+
+/// What a lossless channel does when a value arrives and its queue is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Stop reading the input channel until there is room, so producers wait.
+    #[default]
+    Block,
+    /// Drop the oldest queued value to make room.
+    DropOldest,
+    /// Drop the value that just arrived.
+    DropNewest,
+    /// Stop the worker with [`CompletionReason::QueueOverflow`](crate::CompletionReason::QueueOverflow).
+    Error,
+}
+
+/// When the very first value received on the input channel is emitted.
+///
+/// Only applies to [`EmissionMode::Throttle`] with a leading edge; a debounced
+/// channel always waits for the input to go quiet, and a throttle without a
+/// leading edge holds back every value that opens a window.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FirstValue {
+    /// Emit the first value as soon as it arrives.
+    #[default]
+    Immediate,
+    /// Hold the first value back for a full delay, like every later value.
+    Delayed,
+}
+
+/// What a keyed channel does with the pending value of a key it stops
+/// tracking.
+///
+/// Only keys evicted by [`max_keys`](RateLimitedChannelBuilder::max_keys) can
+/// have a value pending; a key is only idle once it has none.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Send the pending value as soon as the
+    /// [`global_delay`](RateLimitedChannelBuilder::global_delay) allows,
+    /// ignoring the rest of the key's window.
+    #[default]
+    FlushPending,
+    /// Discard the pending value.
+    DropPending,
+}
+
+/// Builder for configuring a rate-limited channel.
+///
+/// ```no_run
+/// use rate_limited_channel_rs::{ClosePolicy, RateLimitedChannelBuilder};
+/// use std::time::Duration;
+/// use tokio::sync::mpsc;
+///
+/// # async fn example() {
+/// let (tx, rx) = mpsc::channel::<i32>(100);
+/// let (mut rate_limited_rx, handle) = RateLimitedChannelBuilder::new(Duration::from_secs(1))
+///     .output_capacity(10)
+///     .close_policy(ClosePolicy::EmitImmediately)
+///     .name("sensor-readings")
+///     .build(rx);
+/// # }
+/// ```
+///
+/// `D` is the callback set by [`on_discard`](Self::on_discard), or `()` if
+/// there is none.
+#[derive(Debug, Clone)]
+pub struct RateLimitedChannelBuilder<D = ()> {
+    delay: Duration,
+    output_capacity: usize,
+    mode: EmissionMode,
+    max_wait: Option<Duration>,
+    leading: bool,
+    trailing: bool,
+    lossless: Option<(usize, OverflowPolicy)>,
+    max_batch_size: Option<usize>,
+    max_batch_delay: Option<Duration>,
+    /// `None` until set, so a lossless channel can default to draining
+    close_policy: Option<ClosePolicy>,
+    first_value: FirstValue,
+    max_keys: Option<usize>,
+    key_idle_timeout: Option<Duration>,
+    eviction_policy: EvictionPolicy,
+    global_delay: Option<Duration>,
+    on_discard: D,
+    name: Option<String>,
+}
+
+impl RateLimitedChannelBuilder {
+    /// Creates a builder for a channel that emits at most one value per `delay`.
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            output_capacity: DEFAULT_OUTPUT_CAPACITY,
+            mode: EmissionMode::default(),
+            max_wait: None,
+            leading: true,
+            trailing: true,
+            lossless: None,
+            max_batch_size: None,
+            max_batch_delay: None,
+            close_policy: None,
+            first_value: FirstValue::default(),
+            max_keys: None,
+            key_idle_timeout: None,
+            eviction_policy: EvictionPolicy::default(),
+            global_delay: None,
+            on_discard: (),
+            name: None,
+        }
+    }
+}
+
+impl<D> RateLimitedChannelBuilder<D> {
+    /// Sets the capacity of the output channel. Must be greater than zero.
+    pub fn output_capacity(mut self, capacity: usize) -> Self {
+        self.output_capacity = capacity;
+        self
+    }
+
+    /// Sets how the worker decides when to emit a value.
+    pub fn mode(mut self, mode: EmissionMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Bounds how long a debounced value can be held back while new values keep
+    /// arriving.
+    ///
+    /// In [`EmissionMode::Debounce`] the latest value is forced out once
+    /// `max_wait` has passed since the first value of a burst, or since the
+    /// last emission while the burst goes on, even if the input never goes
+    /// quiet. A burst ends once the input has been quiet for the delay. A
+    /// `max_wait` shorter than the delay is treated as the delay. Ignored by
+    /// the other modes.
+    pub fn max_wait(mut self, max_wait: Duration) -> Self {
+        self.max_wait = Some(max_wait);
+        self
+    }
+
+    /// Sets whether a throttled channel sends a value as soon as it arrives when
+    /// no window is open. Defaults to `true`.
+    ///
+    /// The value opens a window of one delay during which nothing else is sent
+    /// straight away. With `leading(false)` the value is held back until the
+    /// window ends instead. Ignored by the other modes.
+    pub fn leading(mut self, leading: bool) -> Self {
+        self.leading = leading;
+        self
+    }
+
+    /// Sets whether a throttled channel sends the most recent value received
+    /// during a window once the window ends. Defaults to `true`.
+    ///
+    /// With `trailing(false)` values arriving while a window is open are
+    /// dropped. Ignored by the other modes.
+    pub fn trailing(mut self, trailing: bool) -> Self {
+        self.trailing = trailing;
+        self
+    }
+
+    /// Delivers every value instead of only the most recent one.
+    ///
+    /// Values are queued and sent one per delay in the order they arrived.
+    /// Once `capacity` values are queued, `overflow` decides what happens to
+    /// the next one. [`trailing`](Self::trailing) is ignored since no value is
+    /// dropped for arriving inside a window. With [`EmissionMode::TokenBucket`]
+    /// queued values go out whenever a token is available. Not supported with
+    /// [`EmissionMode::Debounce`].
+    ///
+    /// Unless [`close_policy`](Self::close_policy) says otherwise, the values
+    /// still queued when the input channel closes keep going out one per
+    /// delay, as with [`ClosePolicy::EmitAfterDelay`].
+    pub fn lossless(mut self, capacity: usize, overflow: OverflowPolicy) -> Self {
+        self.lossless = Some((capacity, overflow));
+        self
+    }
+
+    /// Caps how many values go into one batch built by
+    /// [`build_batched`](Self::build_batched). Must be greater than zero.
+    ///
+    /// Once a batch is full the worker leaves further values in the input
+    /// channel until the batch has been sent.
+    pub fn max_batch_size(mut self, max_batch_size: usize) -> Self {
+        self.max_batch_size = Some(max_batch_size);
+        self
+    }
+
+    /// Holds back a batch that isn't full yet until `max_batch_delay` has
+    /// passed since its first value.
+    ///
+    /// Without this a batch goes out as soon as the rate allows. With it, a
+    /// batch is sent once it is full or once it has waited `max_batch_delay`,
+    /// whichever comes first, but still never more often than the delay
+    /// allows.
+    pub fn max_batch_delay(mut self, max_batch_delay: Duration) -> Self {
+        self.max_batch_delay = Some(max_batch_delay);
+        self
+    }
+
+    /// Sets what happens to a pending value when the input channel closes.
+    ///
+    /// Defaults to [`ClosePolicy::DropPending`], or to
+    /// [`ClosePolicy::EmitAfterDelay`] for a [`lossless`](Self::lossless)
+    /// channel.
+    pub fn close_policy(mut self, close_policy: ClosePolicy) -> Self {
+        self.close_policy = Some(close_policy);
+        self
+    }
+
+    /// Sets when the first value received is emitted.
+    pub fn first_value(mut self, first_value: FirstValue) -> Self {
+        self.first_value = first_value;
+        self
+    }
+
+    /// Caps how many keys a channel built by [`build_keyed`](Self::build_keyed)
+    /// tracks at once. Must be greater than zero.
+    ///
+    /// A value for a new key evicts the key that least recently received a
+    /// value once `max_keys` are tracked.
+    pub fn max_keys(mut self, max_keys: usize) -> Self {
+        self.max_keys = Some(max_keys);
+        self
+    }
+
+    /// Evicts a key from a channel built by [`build_keyed`](Self::build_keyed)
+    /// once it has been idle for `key_idle_timeout`.
+    ///
+    /// A key is idle once it has nothing pending and both its most recent value
+    /// and its window are behind it, so evicting it never lets a value through
+    /// early.
+    pub fn key_idle_timeout(mut self, key_idle_timeout: Duration) -> Self {
+        self.key_idle_timeout = Some(key_idle_timeout);
+        self
+    }
+
+    /// Sets what happens to the pending value of an evicted key.
+    ///
+    /// See [`max_keys`](Self::max_keys) and
+    /// [`key_idle_timeout`](Self::key_idle_timeout).
+    pub fn eviction_policy(mut self, eviction_policy: EvictionPolicy) -> Self {
+        self.eviction_policy = eviction_policy;
+        self
+    }
+
+    /// Limits a channel built by [`build_keyed`](Self::build_keyed) to one
+    /// value per `global_delay` across all keys, on top of each key's own
+    /// limit of one value per delay.
+    ///
+    /// Values that are due while the global budget is spent wait their turn.
+    /// Keys take turns round-robin, so a key can't send again while other
+    /// keys are waiting with values that became due before it. A waiting
+    /// key's value is still replaced by newer ones. Values flushed from keys
+    /// evicted by [`max_keys`](Self::max_keys) wait for the budget too, ahead
+    /// of the keys still tracked.
+    pub fn global_delay(mut self, global_delay: Duration) -> Self {
+        self.global_delay = Some(global_delay);
+        self
+    }
+
+    /// Calls `on_discard` with every value the channel discards instead of
+    /// sending, along with the reason.
+    ///
+    /// Use this to release or acknowledge values that hold resources. The
+    /// callback runs on the worker task, so it should be quick; forward the
+    /// value to another channel with `try_send` for anything slower. Values
+    /// merged into another by [`build_with_merge`](Self::build_with_merge)
+    /// aren't discarded.
+    ///
+    /// `T` is the type received on the input channel, so the channel can only
+    /// be built for that type:
+    ///
+    /// ```compile_fail
+    /// use rate_limited_channel_rs::RateLimitedChannelBuilder;
+    /// use std::time::Duration;
+    /// use tokio::sync::mpsc;
+    ///
+    /// # async fn example() {
+    /// let (_tx, rx) = mpsc::channel::<i32>(100);
+    /// let (rate_limited_rx, _handle) = RateLimitedChannelBuilder::new(Duration::from_secs(1))
+    ///     .on_discard(|value: String, _reason| drop(value))
+    ///     .build(rx);
+    /// # }
+    /// ```
+    pub fn on_discard<T, F>(self, on_discard: F) -> RateLimitedChannelBuilder<OnDiscard<T>>
+    where
+        F: Fn(T, DiscardReason) + Send + Sync + 'static,
+    {
+        RateLimitedChannelBuilder {
+            delay: self.delay,
+            output_capacity: self.output_capacity,
+            mode: self.mode,
+            max_wait: self.max_wait,
+            leading: self.leading,
+            trailing: self.trailing,
+            lossless: self.lossless,
+            max_batch_size: self.max_batch_size,
+            max_batch_delay: self.max_batch_delay,
+            close_policy: self.close_policy,
+            first_value: self.first_value,
+            max_keys: self.max_keys,
+            key_idle_timeout: self.key_idle_timeout,
+            eviction_policy: self.eviction_policy,
+            global_delay: self.global_delay,
+            on_discard: OnDiscard::new(on_discard),
+            name: self.name,
+        }
+    }
+
+    /// Names the channel so it can be told apart from others.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Spawns the worker and returns the rate-limited output channel along with
+    /// a handle to the worker.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the output capacity, lossless queue capacity or token bucket
+    /// burst is zero, if both [`leading`](Self::leading) and
+    /// [`trailing`](Self::trailing) are disabled for a throttled channel, or if
+    /// a debounced channel is made lossless.
+    pub fn build<T>(self, input: Receiver<T>) -> (Receiver<T>, RateLimiterHandle<T>)
+    where
+        T: Send + 'static,
+        D: DiscardCallback<T>,
+    {
+        match self.lossless {
+            Some((capacity, overflow)) => self.spawn(input, Queue::new(capacity, overflow)),
+            None => self.spawn(input, Latest::new()),
+        }
+    }
+
+    /// Like [`build`](Self::build), but every value is sent wrapped in
+    /// [`Emitted`], which says when it was received and sent, its place in the
+    /// sequence of sent values and how many input values it stands for.
+    ///
+    /// # Panics
+    ///
+    /// Panics for the same reasons as [`build`](Self::build).
+    pub fn build_emitted<T>(
+        self,
+        input: Receiver<T>,
+    ) -> (Receiver<Emitted<T>>, RateLimiterHandle<T>)
+    where
+        T: Send + 'static,
+        D: DiscardCallback<T>,
+    {
+        match self.lossless {
+            Some((capacity, overflow)) => {
+                self.spawn(input, Stamped::new(Queue::new(capacity, overflow)))
+            }
+            None => self.spawn(input, Stamped::new(Latest::new())),
+        }
+    }
+
+    /// Like [`build`](Self::build), but values that arrive while another is
+    /// pending are combined with `merge` instead of replacing it.
+    ///
+    /// `merge` is called with the pending value and the new one, in that
+    /// order, so everything received between two emissions is folded into the
+    /// next emitted value.
+    ///
+    /// # Panics
+    ///
+    /// Panics for the same reasons as [`build`](Self::build), or if the channel
+    /// is [`lossless`](Self::lossless).
+    pub fn build_with_merge<T, F>(
+        self,
+        input: Receiver<T>,
+        merge: F,
+    ) -> (Receiver<T>, RateLimiterHandle<T>)
+    where
+        T: Send + 'static,
+        F: FnMut(T, T) -> T + Send + 'static,
+        D: DiscardCallback<T>,
+    {
+        assert!(
+            self.lossless.is_none(),
+            "rate-limited channel {}: a merging channel can't be lossless",
+            self.label(),
+        );
+
+        self.spawn(input, Merge::new(merge))
+    }
+
+    /// Like [`build`](Self::build), but every value received between two
+    /// emissions is sent together as one batch.
+    ///
+    /// Batches go out at most once per delay and are never empty. Use
+    /// [`max_batch_size`](Self::max_batch_size) to bound their size and
+    /// [`max_batch_delay`](Self::max_batch_delay) to wait for a batch to fill.
+    ///
+    /// # Panics
+    ///
+    /// Panics for the same reasons as [`build`](Self::build), if the maximum
+    /// batch size is zero, or if the channel is [`lossless`](Self::lossless).
+    pub fn build_batched<T>(
+        self,
+        input: Receiver<T>,
+    ) -> (Receiver<Vec<T>>, RateLimiterHandle<Vec<T>>)
+    where
+        T: Send + 'static,
+        D: DiscardCallback<T>,
+    {
+        let limits = self.batch_limits(None);
+        self.spawn(input, Batch::new(limits, |_: &T| 0))
+    }
+
+    /// Like [`build_batched`](Self::build_batched), but a batch is also full
+    /// once the total of `weigh` over its values reaches `max_weight`, for
+    /// example to cap batches by size in bytes.
+    ///
+    /// The value that takes a batch to or past `max_weight` is still part of
+    /// it.
+    ///
+    /// # Panics
+    ///
+    /// Panics for the same reasons as [`build_batched`](Self::build_batched),
+    /// or if `max_weight` is zero.
+    pub fn build_batched_by_weight<T, W>(
+        self,
+        input: Receiver<T>,
+        max_weight: usize,
+        weigh: W,
+    ) -> (Receiver<Vec<T>>, RateLimiterHandle<Vec<T>>)
+    where
+        T: Send + 'static,
+        W: FnMut(&T) -> usize + Send + 'static,
+        D: DiscardCallback<T>,
+    {
+        let limits = self.batch_limits(Some(max_weight));
+        self.spawn(input, Batch::new(limits, weigh))
+    }
+
+    /// Like [`build`](Self::build), but each value is assigned a key by `key`
+    /// and every key is rate limited on its own.
+    ///
+    /// Only the most recent value per key is kept, and a key's values never
+    /// wait on another key's window, so updates for a busy key can't starve
+    /// those for a quiet one. Each key gets its own [`leading`](Self::leading)
+    /// and [`trailing`](Self::trailing) edges.
+    ///
+    /// Keys are tracked until the worker stops unless
+    /// [`max_keys`](Self::max_keys) or
+    /// [`key_idle_timeout`](Self::key_idle_timeout) is set. An evicted key's
+    /// window is forgotten, so its next value is treated like a new key's.
+    ///
+    /// # Panics
+    ///
+    /// Panics for the same reasons as [`build`](Self::build), if the channel
+    /// isn't throttled, if it is [`lossless`](Self::lossless), or if
+    /// [`max_keys`](Self::max_keys) is zero.
+    pub fn build_keyed<T, K, F>(
+        self,
+        input: Receiver<T>,
+        key: F,
+    ) -> (Receiver<T>, RateLimiterHandle<T>)
+    where
+        T: Send + 'static,
+        K: Hash + Eq + Clone + Send + 'static,
+        F: Fn(&T) -> K + Send + 'static,
+        D: DiscardCallback<T>,
+    {
+        assert!(
+            self.mode == EmissionMode::Throttle,
+            "rate-limited channel {}: a keyed channel must be throttled",
+            self.label(),
+        );
+        assert!(
+            self.lossless.is_none(),
+            "rate-limited channel {}: a keyed channel can't be lossless",
+            self.label(),
+        );
+        assert!(
+            self.max_keys != Some(0),
+            "rate-limited channel {}: max keys must be greater than zero",
+            self.label(),
+        );
+
+        let config = self.worker_config();
+        let limits = KeyLimits {
+            max_keys: self.max_keys,
+            idle_timeout: self.key_idle_timeout,
+            eviction_policy: self.eviction_policy,
+            global_delay: self.global_delay,
+        };
+        self.spawn_schedule(input, Keyed::new(key, &config, limits))
+    }
+
+    /// Checks the batch settings and collects them for the worker.
+    fn batch_limits(&self, max_weight: Option<usize>) -> BatchLimits {
+        assert!(
+            self.lossless.is_none(),
+            "rate-limited channel {}: a batched channel can't be lossless",
+            self.label(),
+        );
+        assert!(
+            self.max_batch_size != Some(0),
+            "rate-limited channel {}: max batch size must be greater than zero",
+            self.label(),
+        );
+        assert!(
+            max_weight != Some(0),
+            "rate-limited channel {}: max batch weight must be greater than zero",
+            self.label(),
+        );
+
+        BatchLimits {
+            max_size: self.max_batch_size,
+            max_weight,
+            max_delay: self.max_batch_delay,
+        }
+    }
+
+    fn spawn<P>(
+        self,
+        input: Receiver<P::Item>,
+        pending: P,
+    ) -> (Receiver<P::Output>, RateLimiterHandle<P::Value>)
+    where
+        P: Pending + Send + 'static,
+        P::Item: Send + 'static,
+        P::Output: Send + 'static,
+        P::Value: Send + 'static,
+        D: DiscardCallback<P::Item>,
+    {
+        let schedule = Paced::new(pending, &self.worker_config());
+        self.spawn_schedule(input, schedule)
+    }
+
+    /// Validates the settings, then spawns a worker that sends the values
+    /// received on `input` as `schedule` allows.
+    fn spawn_schedule<S>(
+        self,
+        input: Receiver<S::Item>,
+        schedule: S,
+    ) -> (Receiver<S::Output>, RateLimiterHandle<S::Value>)
+    where
+        S: Schedule + Send + 'static,
+        S::Item: Send + 'static,
+        S::Output: Send + 'static,
+        S::Value: Send + 'static,
+        D: DiscardCallback<S::Item>,
+    {
+        self.validate();
+
+        let (output_tx, output_rx) = mpsc::channel(self.output_capacity);
+        let (command_tx, command_rx) = mpsc::unbounded_channel();
+
+        let counters = Arc::new(Counters::default());
+
+        let task = tokio::spawn(run_worker(
+            input,
+            output_tx,
+            command_rx,
+            schedule,
+            self.worker_config().close_policy,
+            self.discard(),
+            counters.clone(),
+        ));
+
+        (
+            output_rx,
+            RateLimiterHandle::new(self.name, self.delay, command_tx, counters, task),
+        )
+    }
+
+    pub(crate) fn worker_config(&self) -> WorkerConfig {
+        WorkerConfig {
+            delay: self.delay,
+            mode: self.mode,
+            max_wait: self.max_wait,
+            leading: self.leading,
+            trailing: self.trailing,
+            close_policy: self.close_policy.unwrap_or(match self.lossless {
+                Some(_) => ClosePolicy::EmitAfterDelay,
+                None => ClosePolicy::default(),
+            }),
+            first_value: self.first_value,
+        }
+    }
+
+    /// Panics if the settings can't work together.
+    fn validate(&self) {
+        assert!(
+            self.output_capacity > 0,
+            "rate-limited channel {}: output capacity must be greater than zero",
+            self.label(),
+        );
+        assert!(
+            self.mode != EmissionMode::Throttle || self.leading || self.trailing,
+            "rate-limited channel {}: a throttle needs a leading or trailing edge",
+            self.label(),
+        );
+        if let EmissionMode::TokenBucket { burst } = self.mode {
+            assert!(
+                burst > 0,
+                "rate-limited channel {}: token bucket burst must be greater than zero",
+                self.label(),
+            );
+        }
+        if let Some((capacity, _)) = self.lossless {
+            assert!(
+                capacity > 0,
+                "rate-limited channel {}: lossless queue capacity must be greater than zero",
+                self.label(),
+            );
+            assert!(
+                self.mode != EmissionMode::Debounce,
+                "rate-limited channel {}: a debounced channel can't be lossless",
+                self.label(),
+            );
+        }
+    }
+
+    /// The discard callback for a channel of `T`.
+    fn discard<T>(&self) -> Discard<T>
+    where
+        D: DiscardCallback<T>,
+    {
+        Discard::new(self.on_discard.callback())
+    }
+
+    /// The name to use in panic messages.
+    fn label(&self) -> &str {
+        self.name.as_deref().unwrap_or("<unnamed>")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::Instant;
+
+    #[tokio::test]
+    async fn test_output_capacity() {
+        let (_input_tx, input_rx) = mpsc::channel::<i32>(10);
+
+        let (output_rx, _handle) = RateLimitedChannelBuilder::new(Duration::from_secs(1))
+            .output_capacity(3)
+            .build(input_rx);
+
+        assert_eq!(output_rx.max_capacity(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_default_output_capacity() {
+        let (_input_tx, input_rx) = mpsc::channel::<i32>(10);
+
+        let (output_rx, _handle) =
+            RateLimitedChannelBuilder::new(Duration::from_secs(1)).build(input_rx);
+
+        assert_eq!(output_rx.max_capacity(), DEFAULT_OUTPUT_CAPACITY);
+    }
+
+    #[tokio::test]
+    #[should_panic(
+        expected = "rate-limited channel zero: output capacity must be greater than zero"
+    )]
+    async fn test_zero_output_capacity_panics() {
+        let (_input_tx, input_rx) = mpsc::channel::<i32>(10);
+
+        RateLimitedChannelBuilder::new(Duration::from_secs(1))
+            .output_capacity(0)
+            .name("zero")
+            .build(input_rx);
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "a throttle needs a leading or trailing edge")]
+    async fn test_throttle_without_edges_panics() {
+        let (_input_tx, input_rx) = mpsc::channel::<i32>(10);
+
+        RateLimitedChannelBuilder::new(Duration::from_secs(1))
+            .leading(false)
+            .trailing(false)
+            .build(input_rx);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_first_value_delayed() {
+        const RATE_LIMIT: Duration = Duration::from_secs(1);
+
+        let (input_tx, input_rx) = mpsc::channel::<i32>(10);
+        let (mut output_rx, _handle) = RateLimitedChannelBuilder::new(RATE_LIMIT)
+            .first_value(FirstValue::Delayed)
+            .build(input_rx);
+
+        let start = Instant::now();
+        input_tx.send(1).await.unwrap();
+        input_tx.send(2).await.unwrap();
+
+        assert_eq!(output_rx.recv().await, Some(2));
+        assert_eq!(start.elapsed(), RATE_LIMIT);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_build_with_merge() {
+        let (input_tx, input_rx) = mpsc::channel::<u32>(10);
+        let (mut output_rx, _handle) = RateLimitedChannelBuilder::new(Duration::from_secs(1))
+            .mode(EmissionMode::Debounce)
+            .build_with_merge(input_rx, |a, b| a + b);
+
+        for value in 1..=4 {
+            input_tx.send(value).await.unwrap();
+        }
+
+        assert_eq!(output_rx.recv().await, Some(10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_build_emitted() {
+        let (input_tx, input_rx) = mpsc::channel::<u32>(10);
+        let (mut output_rx, _handle) =
+            RateLimitedChannelBuilder::new(Duration::from_secs(1)).build_emitted(input_rx);
+
+        let start = Instant::now();
+        for value in 1..=4 {
+            input_tx.send(value).await.unwrap();
+        }
+
+        let first = output_rx.recv().await.unwrap();
+        assert_eq!((first.value, first.sequence, first.coalesced), (1, 0, 1));
+
+        let second = output_rx.recv().await.unwrap();
+        assert_eq!((second.value, second.sequence, second.coalesced), (4, 1, 3));
+        assert_eq!(second.received_at, start);
+        assert_eq!(second.emitted_at, start + Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_build_batched() {
+        let (input_tx, input_rx) = mpsc::channel::<u32>(10);
+        let (mut output_rx, _handle) =
+            RateLimitedChannelBuilder::new(Duration::from_secs(1)).build_batched(input_rx);
+
+        let start = Instant::now();
+        for value in 0..5 {
+            input_tx.send(value).await.unwrap();
+        }
+
+        assert_eq!(output_rx.recv().await, Some(vec![0]));
+        assert_eq!(output_rx.recv().await, Some(vec![1, 2, 3, 4]));
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_build_batched_max_batch_size() {
+        let (input_tx, input_rx) = mpsc::channel::<u32>(10);
+        let (mut output_rx, _handle) = RateLimitedChannelBuilder::new(Duration::from_secs(1))
+            .max_batch_size(2)
+            .build_batched(input_rx);
+
+        let start = Instant::now();
+        for value in 0..5 {
+            input_tx.send(value).await.unwrap();
+        }
+
+        // Values beyond a full batch wait for the next one
+        assert_eq!(output_rx.recv().await, Some(vec![0]));
+        assert_eq!(output_rx.recv().await, Some(vec![1, 2]));
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+        assert_eq!(output_rx.recv().await, Some(vec![3, 4]));
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "a merging channel can't be lossless")]
+    async fn test_lossless_merge_panics() {
+        let (_input_tx, input_rx) = mpsc::channel::<u32>(10);
+
+        RateLimitedChannelBuilder::new(Duration::from_secs(1))
+            .lossless(10, OverflowPolicy::Block)
+            .build_with_merge(input_rx, |a, b| a + b);
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "max batch weight must be greater than zero")]
+    async fn test_zero_max_batch_weight_panics() {
+        let (_input_tx, input_rx) = mpsc::channel::<u32>(10);
+
+        RateLimitedChannelBuilder::new(Duration::from_secs(1)).build_batched_by_weight(
+            input_rx,
+            0,
+            |_| 1,
+        );
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "max keys must be greater than zero")]
+    async fn test_zero_max_keys_panics() {
+        let (_input_tx, input_rx) = mpsc::channel::<u32>(10);
+
+        RateLimitedChannelBuilder::new(Duration::from_secs(1))
+            .max_keys(0)
+            .build_keyed(input_rx, |value| *value);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_close_policy_is_applied() {
+        let (input_tx, input_rx) = mpsc::channel::<i32>(10);
+        let (mut output_rx, _handle) = RateLimitedChannelBuilder::new(Duration::from_secs(1))
+            .close_policy(ClosePolicy::EmitImmediately)
+            .build(input_rx);
+
+        input_tx.send(1).await.unwrap();
+        input_tx.send(2).await.unwrap();
+        drop(input_tx);
+
+        assert_eq!(output_rx.recv().await, Some(1));
+        assert_eq!(output_rx.recv().await, Some(2));
+        assert_eq!(output_rx.recv().await, None);
+    }
+}
@@ -0,0 +1,240 @@
+use std::fmt;
+use std::sync::Arc;
+
+use crate::pending::Pushed;
+
+/// Why a rate-limited channel discarded a value instead of sending it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DiscardReason {
+    /// A newer value replaced it before it could be sent, or it was the
+    /// oldest value in a full
+    /// [`OverflowPolicy::DropOldest`](crate::OverflowPolicy::DropOldest) queue.
+    Superseded,
+    /// It could no longer be sent when it arrived: inside a throttle window
+    /// without a trailing edge, or while an
+    /// [`OverflowPolicy::DropNewest`](crate::OverflowPolicy::DropNewest) or
+    /// [`OverflowPolicy::Error`](crate::OverflowPolicy::Error) queue was full.
+    Expired,
+    /// It was still pending when the worker stopped and the
+    /// [`ClosePolicy`](crate::ClosePolicy) discarded it.
+    DroppedOnClose,
+    /// The output receiver was dropped before it could be sent.
+    OutputClosed,
+    /// Its key was evicted with
+    /// [`EvictionPolicy::DropPending`](crate::EvictionPolicy::DropPending).
+    Evicted,
+}
+
+/// The callback given to
+/// [`RateLimitedChannelBuilder::on_discard`](crate::RateLimitedChannelBuilder::on_discard),
+/// for a channel that receives `T`.
+pub struct OnDiscard<T>(Arc<dyn Fn(T, DiscardReason) + Send + Sync>);
+
+impl<T> OnDiscard<T> {
+    pub(crate) fn new<F>(on_discard: F) -> Self
+    where
+        F: Fn(T, DiscardReason) + Send + Sync + 'static,
+    {
+        OnDiscard(Arc::new(on_discard))
+    }
+}
+
+impl<T> Clone for OnDiscard<T> {
+    fn clone(&self) -> Self {
+        OnDiscard(self.0.clone())
+    }
+}
+
+impl<T> fmt::Debug for OnDiscard<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OnDiscard").finish_non_exhaustive()
+    }
+}
+
+/// The discard callback a builder holds, as seen by a channel that receives
+/// `T`: either none, `()`, or an [`OnDiscard<T>`].
+///
+/// This can't be implemented outside this crate.
+pub trait DiscardCallback<T>: sealed::Sealed {
+    #[doc(hidden)]
+    fn callback(&self) -> Option<OnDiscard<T>>;
+}
+
+impl<T> DiscardCallback<T> for () {
+    fn callback(&self) -> Option<OnDiscard<T>> {
+        None
+    }
+}
+
+impl<T> DiscardCallback<T> for OnDiscard<T> {
+    fn callback(&self) -> Option<OnDiscard<T>> {
+        Some(self.clone())
+    }
+}
+
+mod sealed {
+    pub trait Sealed {}
+
+    impl Sealed for () {}
+    impl<T> Sealed for super::OnDiscard<T> {}
+}
+
+/// Hands discarded values to the callback, if there is one. Without one they
+/// are simply dropped.
+pub(crate) struct Discard<T>(Option<OnDiscard<T>>);
+
+impl<T> Discard<T> {
+    pub(crate) fn new(on_discard: Option<OnDiscard<T>>) -> Self {
+        Discard(on_discard)
+    }
+
+    pub(crate) fn discard(&self, value: T, reason: DiscardReason) {
+        if let Some(OnDiscard(on_discard)) = &self.0 {
+            on_discard(value, reason);
+        }
+    }
+
+    /// Discards the value a push handed back, if any.
+    pub(crate) fn pushed(&self, pushed: Pushed<T>) {
+        match pushed {
+            Pushed::Stored | Pushed::Merged => {}
+            Pushed::Replaced(value) => self.discard(value, DiscardReason::Superseded),
+            Pushed::Rejected(value) => self.discard(value, DiscardReason::Expired),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CompletionReason, OverflowPolicy, RateLimitedChannelBuilder};
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    /// Sends 1, 2 and 3 to a channel built from `builder` and closes the input,
+    /// returning what was emitted and what was discarded.
+    async fn discarded(
+        builder: RateLimitedChannelBuilder,
+    ) -> (Vec<i32>, Vec<(i32, DiscardReason)>) {
+        let discarded = Arc::new(Mutex::new(Vec::new()));
+        let (input_tx, input_rx) = mpsc::channel::<i32>(10);
+        let (mut output_rx, _handle) = builder
+            .on_discard({
+                let discarded = discarded.clone();
+                move |value: i32, reason| discarded.lock().unwrap().push((value, reason))
+            })
+            .build(input_rx);
+
+        for value in [1, 2, 3] {
+            input_tx.send(value).await.unwrap();
+        }
+        drop(input_tx);
+
+        let mut emitted = Vec::new();
+        while let Some(value) = output_rx.recv().await {
+            emitted.push(value);
+        }
+
+        let discarded = discarded.lock().unwrap().clone();
+        (emitted, discarded)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_superseded_and_dropped_on_close() {
+        let (emitted, discarded) =
+            discarded(RateLimitedChannelBuilder::new(Duration::from_secs(1))).await;
+
+        assert_eq!(emitted, vec![1]);
+        assert_eq!(
+            discarded,
+            vec![
+                (2, DiscardReason::Superseded),
+                (3, DiscardReason::DroppedOnClose)
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_expired_without_trailing_edge() {
+        let builder = RateLimitedChannelBuilder::new(Duration::from_secs(1)).trailing(false);
+        let (emitted, discarded) = discarded(builder).await;
+
+        assert_eq!(emitted, vec![1]);
+        assert_eq!(
+            discarded,
+            vec![(2, DiscardReason::Expired), (3, DiscardReason::Expired)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_overflow_error_expires_value() {
+        let builder = RateLimitedChannelBuilder::new(Duration::from_secs(1))
+            .lossless(1, OverflowPolicy::Error);
+        let (emitted, discarded) = discarded(builder).await;
+
+        assert_eq!(emitted, vec![1, 2]);
+        assert_eq!(discarded, vec![(3, DiscardReason::Expired)]);
+    }
+
+    /// Sends 1, 2 and 3 to a channel built from `builder`, waits `wait` and
+    /// drops the output receiver, returning why the worker stopped and what
+    /// was discarded.
+    async fn output_closed(
+        builder: RateLimitedChannelBuilder,
+        wait: Duration,
+    ) -> (CompletionReason, Vec<(i32, DiscardReason)>) {
+        let discarded = Arc::new(Mutex::new(Vec::new()));
+        let (input_tx, input_rx) = mpsc::channel::<i32>(10);
+        let (output_rx, handle) = builder
+            .on_discard({
+                let discarded = discarded.clone();
+                move |value: i32, reason| discarded.lock().unwrap().push((value, reason))
+            })
+            .build(input_rx);
+
+        for value in [1, 2, 3] {
+            input_tx.send(value).await.unwrap();
+        }
+        tokio::time::sleep(wait).await;
+        drop(output_rx);
+
+        let reason = handle.join().await.unwrap();
+        let discarded = discarded.lock().unwrap().clone();
+        (reason, discarded)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_output_closed_discards_pending() {
+        let builder = RateLimitedChannelBuilder::new(Duration::from_secs(1));
+        let (reason, discarded) = output_closed(builder, Duration::from_millis(500)).await;
+
+        assert_eq!(reason, CompletionReason::OutputClosed);
+        assert_eq!(
+            discarded,
+            vec![
+                (2, DiscardReason::Superseded),
+                (3, DiscardReason::OutputClosed)
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_output_closed_discards_unsent() {
+        // 1 fills the output channel, 2 is due but has no room and 3 is queued
+        let builder = RateLimitedChannelBuilder::new(Duration::from_secs(1))
+            .output_capacity(1)
+            .lossless(10, OverflowPolicy::DropNewest);
+        let (reason, discarded) = output_closed(builder, Duration::from_millis(1500)).await;
+
+        assert_eq!(reason, CompletionReason::OutputClosed);
+        assert_eq!(
+            discarded,
+            vec![
+                (2, DiscardReason::OutputClosed),
+                (3, DiscardReason::OutputClosed)
+            ]
+        );
+    }
+}
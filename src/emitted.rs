@@ -0,0 +1,236 @@
+use std::collections::VecDeque;
+use tokio::time::Instant;
+
+use crate::pending::{Pending, Pushed};
+
+/// A value sent by a channel built with
+/// [`build_emitted`](crate::RateLimitedChannelBuilder::build_emitted), along
+/// with where it came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Emitted<T> {
+    /// The value itself.
+    pub value: T,
+    /// When the most recent input value that went into it was received.
+    pub received_at: Instant,
+    /// When the worker sent it. The consumer may see it later if the output
+    /// channel is backed up.
+    pub emitted_at: Instant,
+    /// Counts up by one with every value sent, starting at zero.
+    pub sequence: u64,
+    /// How many input values went into it: one, plus every value it
+    /// superseded or was merged with since the previous emission.
+    pub coalesced: u64,
+}
+
+/// Stamps the values of another store with [`Emitted`] metadata.
+///
+/// Keeps one entry per value `inner` holds, with when it last changed and how
+/// many input values went into it.
+pub(crate) struct Stamped<P> {
+    inner: P,
+    entries: VecDeque<(Instant, u64)>,
+    sequence: u64,
+}
+
+impl<P> Stamped<P> {
+    pub(crate) fn new(inner: P) -> Self {
+        Stamped {
+            inner,
+            entries: VecDeque::new(),
+            sequence: 0,
+        }
+    }
+}
+
+impl<P: Pending> Pending for Stamped<P> {
+    type Item = P::Item;
+    type Output = Emitted<P::Output>;
+    type Value = P::Value;
+
+    fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    fn is_lossless(&self) -> bool {
+        self.inner.is_lossless()
+    }
+
+    fn is_full(&self) -> bool {
+        self.inner.is_full()
+    }
+
+    fn hold_until(&self) -> Option<Instant> {
+        self.inner.hold_until()
+    }
+
+    fn push(&mut self, value: P::Item) -> Result<Pushed<P::Item>, P::Item> {
+        let pushed = self.inner.push(value)?;
+        let now = Instant::now();
+
+        match &pushed {
+            Pushed::Stored => self.entries.push_back((now, 1)),
+            Pushed::Merged => {
+                if let Some(entry) = self.entries.back_mut() {
+                    *entry = (now, entry.1 + 1);
+                }
+            }
+            Pushed::Replaced(_) => {
+                // A value replaced by a newer one counts towards it, but one
+                // dropped from a full queue to make room doesn't
+                let replaced = self.entries.pop_front().map_or(0, |(_, count)| count);
+                let carried = if self.inner.is_lossless() {
+                    0
+                } else {
+                    replaced
+                };
+                self.entries.push_back((now, carried + 1));
+            }
+            Pushed::Rejected(_) => {}
+        }
+
+        Ok(pushed)
+    }
+
+    /// Stores that keep values apart send them one at a time; the others send
+    /// everything they hold at once.
+    fn take(&mut self) -> Option<Emitted<P::Output>> {
+        let value = self.inner.take()?;
+
+        let taken = if self.inner.is_empty() {
+            self.entries.len()
+        } else {
+            1
+        };
+        let (received_at, coalesced) = self
+            .entries
+            .drain(..taken)
+            .fold(None, |stamp, (received_at, count)| match stamp {
+                Some((_, total)) => Some((received_at, total + count)),
+                None => Some((received_at, count)),
+            })
+            .unwrap_or((Instant::now(), 1));
+
+        let sequence = self.sequence;
+        self.sequence += 1;
+
+        Some(Emitted {
+            value,
+            received_at,
+            // Stamped again as it is sent
+            emitted_at: Instant::now(),
+            sequence,
+            coalesced,
+        })
+    }
+
+    fn drain(&mut self) -> Vec<P::Item> {
+        self.entries.clear();
+        self.inner.drain()
+    }
+
+    fn items(output: Emitted<P::Output>) -> Vec<P::Item> {
+        P::items(output.value)
+    }
+
+    fn value(output: &Emitted<P::Output>) -> &P::Value {
+        P::value(&output.value)
+    }
+
+    fn sending(output: &mut Emitted<P::Output>) {
+        output.emitted_at = Instant::now();
+    }
+
+    /// The next value without its metadata, which isn't known until it is
+    /// sent.
+    fn peek(&self) -> Option<&P::Value> {
+        self.inner.peek()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pending::{Latest, Queue};
+    use crate::{OverflowPolicy, RateLimitedChannelBuilder};
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+    use tokio::time::sleep;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_latest_counts_superseded_values() {
+        let mut pending = Stamped::new(Latest::new());
+        let start = Instant::now();
+
+        pending.push(1).unwrap();
+        sleep(Duration::from_secs(1)).await;
+        pending.push(2).unwrap();
+        pending.push(3).unwrap();
+        sleep(Duration::from_secs(1)).await;
+
+        let emitted = pending.take().unwrap();
+        assert_eq!(emitted.value, 3);
+        assert_eq!(emitted.received_at, start + Duration::from_secs(1));
+        assert_eq!(emitted.emitted_at, start + Duration::from_secs(2));
+        assert_eq!((emitted.sequence, emitted.coalesced), (0, 3));
+
+        pending.push(4).unwrap();
+        let emitted = pending.take().unwrap();
+        assert_eq!((emitted.sequence, emitted.coalesced), (1, 1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_queue_stamps_each_value() {
+        let mut pending = Stamped::new(Queue::new(10, OverflowPolicy::Block));
+        let start = Instant::now();
+
+        pending.push(1).unwrap();
+        sleep(Duration::from_secs(1)).await;
+        pending.push(2).unwrap();
+
+        let first = pending.take().unwrap();
+        let second = pending.take().unwrap();
+        assert_eq!((first.value, first.received_at), (1, start));
+        assert_eq!(
+            (second.value, second.received_at),
+            (2, start + Duration::from_secs(1))
+        );
+        assert_eq!((first.coalesced, second.coalesced), (1, 1));
+        assert_eq!((first.sequence, second.sequence), (0, 1));
+    }
+
+    #[test]
+    fn test_drop_oldest_doesnt_carry_dropped_count() {
+        let mut pending = Stamped::new(Queue::new(2, OverflowPolicy::DropOldest));
+
+        for value in 0..5 {
+            pending.push(value).unwrap();
+        }
+
+        let first = pending.take().unwrap();
+        let second = pending.take().unwrap();
+        assert_eq!((first.value, first.coalesced), (3, 1));
+        assert_eq!((second.value, second.coalesced), (4, 1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stamped_when_sent_not_when_due() {
+        let (input_tx, input_rx) = mpsc::channel::<i32>(10);
+        let (mut output_rx, _handle) = RateLimitedChannelBuilder::new(Duration::from_secs(1))
+            .output_capacity(1)
+            .build_emitted(input_rx);
+        let start = Instant::now();
+
+        input_tx.send(1).await.unwrap();
+        input_tx.send(2).await.unwrap();
+        // 2 is due after a second, but 1 fills the output channel until now
+        sleep(Duration::from_secs(5)).await;
+
+        let first = output_rx.recv().await.unwrap();
+        assert_eq!((first.value, first.emitted_at), (1, start));
+
+        let second = output_rx.recv().await.unwrap();
+        assert_eq!(second.value, 2);
+        assert_eq!(second.received_at, start);
+        assert_eq!(second.emitted_at, start + Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,102 @@
+use std::time::Duration;
+use tokio::sync::mpsc::Receiver;
+
+use crate::{to_rate_limited_channel, EmissionMode, RateLimitedChannelBuilder};
+
+#[cfg(feature = "stream")]
+use crate::RateLimitedStream;
+#[cfg(feature = "stream")]
+use futures_core::Stream;
+
+/// Rate limiting adapters for an input channel.
+///
+/// Each adapter spawns a worker, like [`to_rate_limited_channel`], and the
+/// worker keeps running until the input channel closes. Use
+/// [`RateLimitedChannelBuilder`] for a [`RateLimiterHandle`](crate::RateLimiterHandle)
+/// or further options.
+pub trait ReceiverExt<T> {
+    /// Emits the most recent value at most once per `delay`.
+    ///
+    /// Shorthand for [`to_rate_limited_channel`].
+    fn rate_limited(self, delay: Duration) -> Receiver<T>;
+
+    /// Emits the most recent value once no new value has arrived for `delay`.
+    ///
+    /// Shorthand for a [`RateLimitedChannelBuilder`] in [`EmissionMode::Debounce`].
+    fn debounced(self, delay: Duration) -> Receiver<T>;
+}
+
+impl<T: Send + 'static> ReceiverExt<T> for Receiver<T> {
+    fn rate_limited(self, delay: Duration) -> Receiver<T> {
+        to_rate_limited_channel(self, delay)
+    }
+
+    fn debounced(self, delay: Duration) -> Receiver<T> {
+        let (output, _handle) = RateLimitedChannelBuilder::new(delay)
+            .mode(EmissionMode::Debounce)
+            .build(self);
+        output
+    }
+}
+
+/// Rate limiting adapters for a [`Stream`].
+#[cfg(feature = "stream")]
+pub trait RateLimitStreamExt: Stream + Sized {
+    /// Yields the most recent item at most once per `delay`.
+    ///
+    /// Shorthand for [`RateLimitedStream::new`].
+    fn throttle_latest(self, delay: Duration) -> RateLimitedStream<Self> {
+        RateLimitedStream::new(self, delay)
+    }
+}
+
+#[cfg(feature = "stream")]
+impl<S: Stream> RateLimitStreamExt for S {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+    use tokio::time::{sleep, Instant};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limited() {
+        let (input_tx, input_rx) = mpsc::channel::<i32>(10);
+        let mut output_rx = input_rx.rate_limited(Duration::from_secs(1));
+
+        let start = Instant::now();
+        for value in [1, 2, 3] {
+            input_tx.send(value).await.unwrap();
+        }
+
+        assert_eq!(output_rx.recv().await, Some(1));
+        assert_eq!(output_rx.recv().await, Some(3));
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_debounced() {
+        let (input_tx, input_rx) = mpsc::channel::<i32>(10);
+        let mut output_rx = input_rx.debounced(Duration::from_secs(1));
+
+        let start = Instant::now();
+        input_tx.send(1).await.unwrap();
+        sleep(Duration::from_millis(500)).await;
+        input_tx.send(2).await.unwrap();
+
+        assert_eq!(output_rx.recv().await, Some(2));
+        assert_eq!(start.elapsed(), Duration::from_millis(1500));
+    }
+
+    #[cfg(feature = "stream")]
+    #[tokio::test(start_paused = true)]
+    async fn test_throttle_latest() {
+        use tokio_stream::StreamExt;
+
+        let items = tokio_stream::iter(1..=3)
+            .throttle_latest(Duration::from_secs(1))
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(items, vec![1]);
+    }
+}
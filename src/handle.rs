@@ -0,0 +1,485 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::oneshot;
+use tokio::task::{JoinError, JoinHandle};
+use tokio::time::Instant;
+
+use crate::stats::{Counters, Evictions, Stats};
+
+/// Why a rate limiter worker stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompletionReason {
+    /// Every sender of the input channel was dropped.
+    InputClosed,
+    /// The output receiver was dropped.
+    OutputClosed,
+    /// [`RateLimiterHandle::shutdown`] was called.
+    Shutdown,
+    /// A value arrived while the queue of a lossless channel was full and its
+    /// overflow policy is [`OverflowPolicy::Error`](crate::OverflowPolicy::Error).
+    QueueOverflow,
+}
+
+/// What a worker is doing at the moment, as returned by
+/// [`RateLimiterHandle::snapshot`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Snapshot {
+    /// Whether any value is waiting to be sent.
+    pub pending: bool,
+    /// The earliest time the next value may be sent. Already passed if one
+    /// could go out straight away. For a keyed channel, when the next pending
+    /// value is due, or when the global budget allows one if none is pending.
+    pub next_emit_at: Instant,
+    /// How long ago the last value was sent, or `None` if none has been sent.
+    pub since_last_emit: Option<Duration>,
+    /// Whether the channel is [paused](RateLimiterHandle::pause).
+    pub paused: bool,
+}
+
+/// Called by the worker with a snapshot of its state and the next value it
+/// would send, if any.
+pub(crate) type Inspect<T> = Box<dyn FnOnce(Snapshot, Option<&T>) + Send>;
+
+/// Messages sent from a [`RateLimiterHandle`] to its worker.
+pub(crate) enum Command<T> {
+    Shutdown,
+    SetDelay(Duration),
+    Pause,
+    Resume,
+    Flush { reset_window: bool },
+    Inspect(Inspect<T>),
+}
+
+/// Handle to the worker task behind a rate-limited channel.
+///
+/// Dropping the handle leaves the worker running; it keeps going until the
+/// input or output channel closes.
+///
+/// `T` is the type of the values waiting to be sent: the type sent on the
+/// output channel, such as `Vec<T>` for a batched channel, or the type inside
+/// [`Emitted`](crate::Emitted) for a channel built with
+/// [`build_emitted`](crate::RateLimitedChannelBuilder::build_emitted).
+pub struct RateLimiterHandle<T> {
+    name: Option<String>,
+    delay: Mutex<Duration>,
+    paused: AtomicBool,
+    commands: UnboundedSender<Command<T>>,
+    counters: Arc<Counters>,
+    task: JoinHandle<CompletionReason>,
+}
+
+impl<T> fmt::Debug for RateLimiterHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimiterHandle")
+            .field("name", &self.name)
+            .field("delay", &self.delay)
+            .field("paused", &self.paused)
+            .field("counters", &self.counters)
+            .field("task", &self.task)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T> RateLimiterHandle<T> {
+    pub(crate) fn new(
+        name: Option<String>,
+        delay: Duration,
+        commands: UnboundedSender<Command<T>>,
+        counters: Arc<Counters>,
+        task: JoinHandle<CompletionReason>,
+    ) -> Self {
+        Self {
+            name,
+            delay: Mutex::new(delay),
+            paused: AtomicBool::new(false),
+            commands,
+            counters,
+            task,
+        }
+    }
+
+    /// The name given to the channel through [`RateLimitedChannelBuilder::name`](crate::RateLimitedChannelBuilder::name).
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// The delay the worker is currently using.
+    pub fn delay(&self) -> Duration {
+        *self.delay.lock().unwrap()
+    }
+
+    /// Changes the delay without recreating the channel or losing pending
+    /// values.
+    ///
+    /// A wait that is in progress is shortened or lengthened so that it ends
+    /// the new delay after it began, sending straight away if that time has
+    /// already passed. For a token bucket, tokens already spent are paid back
+    /// at the new rate. Keyed channels apply the new delay to every key.
+    pub fn set_delay(&self, delay: Duration) {
+        *self.delay.lock().unwrap() = delay;
+        // The worker has already finished if it can't receive the command
+        let _ = self.commands.send(Command::SetDelay(delay));
+    }
+
+    /// Stops sending values until [`resume`](Self::resume) is called.
+    ///
+    /// Producers aren't affected: input keeps being read and combined just as
+    /// it would be otherwise, so only the latest value (or the merged value,
+    /// batch or queue) is waiting when the channel resumes. A lossless queue
+    /// that fills up while paused follows its
+    /// [`OverflowPolicy`](crate::OverflowPolicy). The close policy still
+    /// applies if the worker stops while paused, and a keyed channel still
+    /// flushes the pending values of keys it evicts.
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Relaxed);
+        // The worker has already finished if it can't receive the command
+        let _ = self.commands.send(Command::Pause);
+    }
+
+    /// Starts sending values again after [`pause`](Self::pause).
+    ///
+    /// Pending values go out as the rate allows: straight away if the delay
+    /// has already elapsed, otherwise once it does.
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::Relaxed);
+        // The worker has already finished if it can't receive the command
+        let _ = self.commands.send(Command::Resume);
+    }
+
+    /// Returns `true` if the channel has been paused and not yet resumed.
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Sends the pending value now instead of waiting for the rest of the
+    /// window.
+    ///
+    /// Everything pending goes out: the latest or merged value, the current
+    /// batch, every queued value of a lossless channel, or the pending value of
+    /// every key. This happens even while the channel is
+    /// [paused](Self::pause).
+    ///
+    /// With `reset_window` the flushed value counts like any other, so the
+    /// next one waits a full delay (or spends a token). Without it the flush
+    /// is free and the current window keeps its original end.
+    pub fn flush(&self, reset_window: bool) {
+        // The worker has already finished if it can't receive the command
+        let _ = self.commands.send(Command::Flush { reset_window });
+    }
+
+    /// Reports what the worker is doing without disturbing it.
+    ///
+    /// Returns `None` if the worker has finished.
+    pub async fn snapshot(&self) -> Option<Snapshot> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.inspect(Box::new(move |snapshot, _| {
+            let _ = reply_tx.send(snapshot);
+        }));
+
+        reply_rx.await.ok()
+    }
+
+    /// Like [`snapshot`](Self::snapshot), but also returns a clone of the value
+    /// that would be sent next, or `None` if nothing is pending.
+    ///
+    /// For a lossless channel the value is the next one in the queue, and for
+    /// an emitted channel it is the value without its metadata, which isn't
+    /// known until it is sent.
+    pub async fn snapshot_with_value(&self) -> Option<(Snapshot, Option<T>)>
+    where
+        T: Clone + Send + 'static,
+    {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.inspect(Box::new(move |snapshot, value| {
+            let _ = reply_tx.send((snapshot, value.cloned()));
+        }));
+
+        reply_rx.await.ok()
+    }
+
+    fn inspect(&self, inspect: Inspect<T>) {
+        // The reply sender is dropped along with the command if the worker has
+        // already finished
+        let _ = self.commands.send(Command::Inspect(inspect));
+    }
+
+    /// Asks the worker to stop.
+    ///
+    /// The worker stops reading input and handles any pending value according
+    /// to its [`ClosePolicy`](crate::ClosePolicy), just as if the input channel
+    /// had closed. Use [`join`](Self::join) to wait for it to finish.
+    ///
+    /// The command is handled even while the output channel is full. With
+    /// [`ClosePolicy::DropPending`](crate::ClosePolicy::DropPending) the
+    /// worker then stops straight away, dropping a due value that doesn't fit.
+    /// With [`EmitImmediately`](crate::ClosePolicy::EmitImmediately) or
+    /// [`EmitAfterDelay`](crate::ClosePolicy::EmitAfterDelay) it sends every
+    /// pending value first, so it only finishes once the consumer has made room
+    /// for them or dropped the output receiver.
+    pub fn shutdown(&self) {
+        // The worker has already finished if it can't receive the command
+        let _ = self.commands.send(Command::Shutdown);
+    }
+
+    /// Counts of what the worker has done with the values it received so far.
+    ///
+    /// Cheap enough to call often: it only reads a few atomic counters and
+    /// doesn't involve the worker.
+    pub fn stats(&self) -> Stats {
+        self.counters.stats()
+    }
+
+    /// How many keys a channel built by
+    /// [`build_keyed`](crate::RateLimitedChannelBuilder::build_keyed) has
+    /// evicted so far.
+    pub fn evictions(&self) -> Evictions {
+        self.counters.evictions()
+    }
+
+    /// Returns `true` once the worker has finished.
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    /// Waits for the worker to finish and returns why it stopped.
+    ///
+    /// Returns an error if the worker panicked or its runtime shut down first.
+    pub async fn join(self) -> Result<CompletionReason, JoinError> {
+        self.task.await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ClosePolicy, RateLimitedChannelBuilder};
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+    use tokio::time::Instant;
+
+    #[tokio::test]
+    async fn test_join_after_input_closed() {
+        let (input_tx, input_rx) = mpsc::channel::<i32>(10);
+        let (mut output_rx, handle) =
+            RateLimitedChannelBuilder::new(Duration::from_millis(10)).build(input_rx);
+
+        input_tx.send(1).await.unwrap();
+        drop(input_tx);
+
+        assert_eq!(output_rx.recv().await, Some(1));
+        assert_eq!(handle.join().await.unwrap(), CompletionReason::InputClosed);
+    }
+
+    #[tokio::test]
+    async fn test_join_after_output_closed() {
+        let (_input_tx, input_rx) = mpsc::channel::<i32>(10);
+        let (output_rx, handle) =
+            RateLimitedChannelBuilder::new(Duration::from_millis(10)).build(input_rx);
+
+        drop(output_rx);
+
+        assert_eq!(handle.join().await.unwrap(), CompletionReason::OutputClosed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shutdown_applies_close_policy() {
+        let (input_tx, input_rx) = mpsc::channel::<i32>(10);
+        let (mut output_rx, handle) = RateLimitedChannelBuilder::new(Duration::from_secs(1))
+            .close_policy(ClosePolicy::EmitImmediately)
+            .build(input_rx);
+
+        input_tx.send(1).await.unwrap();
+        assert_eq!(output_rx.recv().await, Some(1));
+        input_tx.send(2).await.unwrap();
+        tokio::task::yield_now().await;
+
+        handle.shutdown();
+
+        assert_eq!(output_rx.recv().await, Some(2));
+        assert_eq!(output_rx.recv().await, None);
+        assert_eq!(handle.join().await.unwrap(), CompletionReason::Shutdown);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shutdown_while_output_full() {
+        let (input_tx, input_rx) = mpsc::channel::<i32>(10);
+        let (mut output_rx, handle) = RateLimitedChannelBuilder::new(Duration::from_secs(1))
+            .output_capacity(1)
+            .close_policy(ClosePolicy::DropPending)
+            .build(input_rx);
+
+        input_tx.send(1).await.unwrap();
+        input_tx.send(2).await.unwrap();
+        // 1 fills the output channel and 2 is due but has no room
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        handle.shutdown();
+        let counters = handle.counters.clone();
+
+        let reason = tokio::time::timeout(Duration::from_secs(1), handle.join()).await;
+        assert_eq!(reason.unwrap().unwrap(), CompletionReason::Shutdown);
+        assert_eq!(output_rx.recv().await, Some(1));
+        assert_eq!(output_rx.recv().await, None);
+
+        let stats = counters.stats();
+        assert_eq!((stats.emitted, stats.dropped_at_close), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_while_idle() {
+        let (_input_tx, input_rx) = mpsc::channel::<i32>(10);
+        let (mut output_rx, handle) = RateLimitedChannelBuilder::new(Duration::from_secs(1))
+            .name("idle")
+            .build(input_rx);
+
+        assert_eq!(handle.name(), Some("idle"));
+        assert_eq!(handle.delay(), Duration::from_secs(1));
+
+        handle.set_delay(Duration::from_millis(250));
+        assert_eq!(handle.delay(), Duration::from_millis(250));
+
+        handle.pause();
+        assert!(handle.is_paused());
+        handle.resume();
+        assert!(!handle.is_paused());
+        assert!(!handle.is_finished());
+
+        handle.shutdown();
+
+        assert_eq!(output_rx.recv().await, None);
+        assert_eq!(handle.join().await.unwrap(), CompletionReason::Shutdown);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_snapshot() {
+        let (input_tx, input_rx) = mpsc::channel::<i32>(10);
+        let (mut output_rx, handle) =
+            RateLimitedChannelBuilder::new(Duration::from_secs(1)).build(input_rx);
+
+        let start = Instant::now();
+        let snapshot = handle.snapshot().await.unwrap();
+        assert!(!snapshot.pending);
+        assert_eq!(snapshot.since_last_emit, None);
+
+        input_tx.send(1).await.unwrap();
+        assert_eq!(output_rx.recv().await, Some(1));
+        input_tx.send(2).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        let (snapshot, value) = handle.snapshot_with_value().await.unwrap();
+        assert_eq!(
+            snapshot,
+            Snapshot {
+                pending: true,
+                next_emit_at: start + Duration::from_secs(1),
+                since_last_emit: Some(Duration::from_millis(300)),
+                paused: false,
+            }
+        );
+        assert_eq!(value, Some(2));
+
+        // The snapshot didn't consume the value
+        assert_eq!(output_rx.recv().await, Some(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_snapshot_while_output_full() {
+        let (input_tx, input_rx) = mpsc::channel::<i32>(10);
+        let (mut output_rx, handle) = RateLimitedChannelBuilder::new(Duration::from_secs(1))
+            .output_capacity(1)
+            .build(input_rx);
+
+        input_tx.send(1).await.unwrap();
+        input_tx.send(2).await.unwrap();
+        // 1 fills the output channel and 2 is due but has no room
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        let snapshot = tokio::time::timeout(Duration::from_secs(1), handle.snapshot_with_value());
+        let (snapshot, value) = snapshot.await.unwrap().unwrap();
+        assert!(snapshot.pending);
+        assert_eq!(value, Some(2));
+
+        assert_eq!(output_rx.recv().await, Some(1));
+        assert_eq!(output_rx.recv().await, Some(2));
+        let (snapshot, value) = handle.snapshot_with_value().await.unwrap();
+        assert!(!snapshot.pending);
+        assert_eq!(value, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_snapshot_emitted() {
+        let (input_tx, input_rx) = mpsc::channel::<i32>(10);
+        let (mut output_rx, handle) = RateLimitedChannelBuilder::new(Duration::from_secs(1))
+            .output_capacity(1)
+            .build_emitted(input_rx);
+
+        input_tx.send(1).await.unwrap();
+        input_tx.send(2).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        // 2 is waiting for the delay
+        let (snapshot, value) = handle.snapshot_with_value().await.unwrap();
+        assert!(snapshot.pending);
+        assert_eq!(value, Some(2));
+
+        // 2 is due, but 1 is still filling the output channel
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let (snapshot, value) = handle.snapshot_with_value().await.unwrap();
+        assert!(snapshot.pending);
+        assert_eq!(value, Some(2));
+
+        assert_eq!(output_rx.recv().await.unwrap().value, 1);
+        assert_eq!(output_rx.recv().await.unwrap().value, 2);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_after_finish() {
+        let (_input_tx, input_rx) = mpsc::channel::<i32>(10);
+        let (_output_rx, handle) =
+            RateLimitedChannelBuilder::new(Duration::from_secs(1)).build(input_rx);
+
+        handle.shutdown();
+        while !handle.is_finished() {
+            tokio::task::yield_now().await;
+        }
+
+        assert_eq!(handle.snapshot().await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stats() {
+        let (input_tx, input_rx) = mpsc::channel::<i32>(10);
+        let (mut output_rx, handle) = RateLimitedChannelBuilder::new(Duration::from_secs(1))
+            .output_capacity(1)
+            .build(input_rx);
+
+        for value in [1, 2, 3] {
+            input_tx.send(value).await.unwrap();
+        }
+
+        // 3 is ready after a second but the output channel is full until 1 is
+        // received
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(output_rx.recv().await, Some(1));
+        assert_eq!(output_rx.recv().await, Some(3));
+
+        input_tx.send(4).await.unwrap();
+        drop(input_tx);
+        assert_eq!(output_rx.recv().await, None);
+
+        assert_eq!(
+            handle.stats(),
+            Stats {
+                received: 4,
+                superseded: 1,
+                expired: 0,
+                emitted: 2,
+                dropped_at_close: 1,
+                send_wait: Duration::from_millis(500),
+            }
+        );
+    }
+}
@@ -0,0 +1,803 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::hash::Hash;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::discard::{Discard, DiscardReason};
+use crate::stats::Counters;
+use crate::worker::{Outbox, Schedule, WorkerConfig};
+use crate::{EvictionPolicy, FirstValue};
+
+/// Limits that apply to a keyed channel as a whole rather than to each key.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct KeyLimits {
+    /// The most keys tracked at once. The least recently seen key is evicted
+    /// to make room for a new one.
+    pub(crate) max_keys: Option<usize>,
+    /// How long a key is tracked once it has nothing pending and its window
+    /// has ended.
+    pub(crate) idle_timeout: Option<Duration>,
+    /// What happens to an evicted key's pending value.
+    pub(crate) eviction_policy: EvictionPolicy,
+    /// The shortest time between two values sent for any keys.
+    pub(crate) global_delay: Option<Duration>,
+}
+
+/// Identifies an entry in the send schedule. The counter keeps entries for the
+/// same instant apart and in the order they were scheduled.
+type Slot = (Instant, u64);
+
+/// What the worker tracks for each key.
+struct KeyState<T> {
+    /// The most recent value for the key that hasn't been sent yet.
+    pending: Option<T>,
+    /// The earliest time the key's next value may be sent. A window is open
+    /// for as long as this is in the future.
+    ready_at: Instant,
+    /// Where the pending value sits in the schedule.
+    slot: Option<Slot>,
+    /// Whether the pending value is due and waiting in the ready queue.
+    queued: bool,
+    /// When the key's most recent value arrived, and where the key sits in
+    /// the recency order.
+    seen: Slot,
+    /// When the key went idle, and where it sits in the idle order. Only set
+    /// while the key has nothing pending.
+    idle: Option<Slot>,
+}
+
+/// The per-key state, the order in which pending values are due, the keys
+/// whose values are due and waiting for the global budget, the order in which
+/// keys were last seen, and the order in which they went idle.
+struct Keys<K, T> {
+    states: HashMap<K, KeyState<T>>,
+    schedule: BTreeMap<Slot, K>,
+    ready: VecDeque<K>,
+    recency: BTreeMap<Slot, K>,
+    idle: BTreeMap<Slot, K>,
+    next_slot: u64,
+}
+
+impl<K: Hash + Eq + Clone, T> Keys<K, T> {
+    fn new() -> Self {
+        Keys {
+            states: HashMap::new(),
+            schedule: BTreeMap::new(),
+            ready: VecDeque::new(),
+            recency: BTreeMap::new(),
+            idle: BTreeMap::new(),
+            next_slot: 0,
+        }
+    }
+
+    fn next_slot(&mut self, at: Instant) -> Slot {
+        let slot = (at, self.next_slot);
+        self.next_slot += 1;
+        slot
+    }
+
+    /// Returns the state for `key`, tracking the key if it is new, and marks
+    /// the key as the most recently seen.
+    fn touch(&mut self, key: &K, now: Instant) -> &mut KeyState<T> {
+        let seen = self.next_slot(now);
+        self.recency.insert(seen, key.clone());
+
+        let state = self.states.entry(key.clone()).or_insert(KeyState {
+            pending: None,
+            ready_at: now,
+            slot: None,
+            queued: false,
+            seen,
+            idle: None,
+        });
+        if state.seen != seen {
+            self.recency.remove(&state.seen);
+            state.seen = seen;
+        }
+
+        state
+    }
+
+    /// The least recently seen key.
+    fn least_recent(&self) -> Option<&K> {
+        self.recency.values().next()
+    }
+
+    /// The key that has been idle the longest and when it went idle.
+    fn longest_idle(&self) -> Option<(&K, Instant)> {
+        self.idle
+            .first_key_value()
+            .map(|((idle_since, _), key)| (key, *idle_since))
+    }
+
+    /// Keeps `key`'s place in the idle order up to date. A key goes idle once
+    /// it has nothing pending and both its most recent value and its window
+    /// are behind it.
+    fn update_idle(&mut self, key: &K) {
+        let Some(state) = self.states.get_mut(key) else {
+            return;
+        };
+
+        let idle_since = state
+            .pending
+            .is_none()
+            .then(|| state.seen.0.max(state.ready_at));
+        if state.idle.map(|(at, _)| at) == idle_since {
+            return;
+        }
+        if let Some(slot) = state.idle.take() {
+            self.idle.remove(&slot);
+        }
+
+        if let Some(at) = idle_since {
+            let slot = (at, self.next_slot);
+            self.next_slot += 1;
+            self.idle.insert(slot, key.clone());
+            state.idle = Some(slot);
+        }
+    }
+
+    /// Stops tracking `key` and returns its pending value, if any.
+    fn evict(&mut self, key: &K) -> Option<T> {
+        let state = self.states.remove(key)?;
+        self.recency.remove(&state.seen);
+        if let Some(slot) = state.idle {
+            self.idle.remove(&slot);
+        }
+        if let Some(slot) = state.slot {
+            self.schedule.remove(&slot);
+        }
+        if state.queued {
+            self.ready.retain(|queued| queued != key);
+        }
+
+        state.pending
+    }
+
+    /// When the next pending value is due, if there is one.
+    fn next_due(&self) -> Option<Instant> {
+        self.schedule.keys().next().map(|(at, _)| *at)
+    }
+
+    /// Makes sure `key`'s pending value is due at `at`, unless it is already
+    /// waiting in the ready queue.
+    fn schedule(&mut self, key: &K, at: Instant) {
+        let state = self.states.get_mut(key).expect("scheduled key is tracked");
+
+        if state.queued
+            || state
+                .slot
+                .is_some_and(|(scheduled_at, _)| scheduled_at == at)
+        {
+            return;
+        }
+        if let Some(slot) = state.slot.take() {
+            self.schedule.remove(&slot);
+        }
+
+        let slot = (at, self.next_slot);
+        self.next_slot += 1;
+        self.schedule.insert(slot, key.clone());
+        state.slot = Some(slot);
+    }
+
+    /// Moves every window still open at `now` so that it ends `new_delay`
+    /// rather than `delay` after it began, along with any value waiting for
+    /// it to end.
+    fn change_delay(&mut self, delay: Duration, new_delay: Duration, now: Instant) {
+        let mut rescheduled = Vec::new();
+        let mut changed = Vec::new();
+
+        for (key, state) in &mut self.states {
+            if state.ready_at <= now {
+                continue;
+            }
+
+            state.ready_at = (state.ready_at + new_delay)
+                .checked_sub(delay)
+                .unwrap_or(now);
+            if let Some(slot) = state.slot {
+                rescheduled.push((slot, key.clone(), state.ready_at.max(now)));
+            } else {
+                changed.push(key.clone());
+            }
+        }
+
+        for key in changed {
+            self.update_idle(&key);
+        }
+
+        // Keep values that end up due at the same time in their original order
+        rescheduled.sort_by_key(|(slot, _, _)| *slot);
+        for (_, key, at) in rescheduled {
+            self.schedule(&key, at);
+        }
+    }
+
+    /// Removes every pending value, those waiting in the ready queue first and
+    /// then the rest in the order they are due.
+    fn take_all(&mut self) -> Vec<(K, T)> {
+        let scheduled = std::mem::take(&mut self.schedule).into_values();
+        let keys = self.ready.drain(..).chain(scheduled).collect::<Vec<_>>();
+
+        keys.into_iter()
+            .filter_map(|key| {
+                let state = self.states.get_mut(&key)?;
+                state.slot = None;
+                state.queued = false;
+                let value = state.pending.take()?;
+                Some((key, value))
+            })
+            .collect()
+    }
+
+    /// The pending value that would be sent next.
+    fn peek(&self) -> Option<&T> {
+        let key = self
+            .ready
+            .front()
+            .or_else(|| self.schedule.values().next())?;
+
+        self.states.get(key)?.pending.as_ref()
+    }
+
+    /// Moves every key whose pending value is due at or before `now` to the
+    /// back of the ready queue, in the order they became due.
+    fn queue_due(&mut self, now: Instant) {
+        while let Some(entry) = self.schedule.first_entry() {
+            if entry.key().0 > now {
+                return;
+            }
+
+            let key = entry.remove();
+            let state = self.states.get_mut(&key).expect("scheduled key is tracked");
+            state.slot = None;
+            state.queued = true;
+            self.ready.push_back(key);
+        }
+    }
+
+    /// Removes the pending value of the key at the front of the ready queue.
+    fn take_ready(&mut self) -> Option<(K, T)> {
+        let key = self.ready.pop_front()?;
+        let state = self.states.get_mut(&key).expect("queued key is tracked");
+        state.queued = false;
+        let value = state.pending.take().expect("queued key has a value");
+
+        Some((key, value))
+    }
+
+    /// Removes the pending value that is due soonest, along with when it is
+    /// due.
+    fn take_scheduled(&mut self) -> Option<(Instant, T)> {
+        let ((due_at, _), key) = self.schedule.pop_first()?;
+        let state = self.states.get_mut(&key).expect("scheduled key is tracked");
+        state.slot = None;
+        let value = state.pending.take().expect("scheduled key has a value");
+
+        Some((due_at, value))
+    }
+}
+
+/// Schedules a keyed channel, where every key is rate limited on its own.
+///
+/// Every value is assigned a key by `key_of`. Only the most recent value per
+/// key is kept, and each key gets its own throttle window, so a busy key
+/// never holds back the values of a quiet one. [`FirstValue::Delayed`] applies
+/// to the first value of every key.
+///
+/// `limits` bounds how many keys are tracked. An evicted key's window is
+/// forgotten along with it, so its next value is treated like a new key's.
+///
+/// With a global delay, values that are due wait in a ready queue until the
+/// channel as a whole may send again. Each key appears in the queue at most
+/// once and goes to the back after it is served, so the global budget is
+/// shared round-robin between keys with values pending. Values flushed from
+/// evicted keys wait for the budget too, ahead of the ready queue.
+pub(crate) struct Keyed<K, T, F> {
+    keys: Keys<K, T>,
+    /// Values of keys evicted with [`EvictionPolicy::FlushPending`], which are
+    /// due but still wait for the global budget.
+    flushed: VecDeque<T>,
+    key_of: F,
+    delay: Duration,
+    leading: bool,
+    trailing: bool,
+    first_value: FirstValue,
+    limits: KeyLimits,
+    /// The earliest time the global budget allows the next value.
+    global_ready_at: Instant,
+}
+
+impl<K, T, F> Keyed<K, T, F>
+where
+    K: Hash + Eq + Clone,
+{
+    pub(crate) fn new(key_of: F, config: &WorkerConfig, limits: KeyLimits) -> Self {
+        Keyed {
+            keys: Keys::new(),
+            flushed: VecDeque::new(),
+            key_of,
+            delay: config.delay,
+            leading: config.leading,
+            trailing: config.trailing,
+            first_value: config.first_value,
+            limits,
+            global_ready_at: Instant::now(),
+        }
+    }
+
+    /// Moves the global budget on after a value went out at `now`.
+    fn spent(&mut self, now: Instant) {
+        if let Some(global_delay) = self.limits.global_delay {
+            self.global_ready_at = now + global_delay;
+        }
+    }
+
+    /// Moves `key`'s window on after its value went out at `now`, along with
+    /// the global budget.
+    fn sent(&mut self, key: &K, now: Instant) {
+        self.spent(now);
+
+        let state = self.keys.states.get_mut(key).expect("sent key is tracked");
+        state.ready_at = if self.leading {
+            now + self.delay
+        } else {
+            // Without a leading edge the next value opens a fresh window
+            now
+        };
+        self.keys.update_idle(key);
+    }
+}
+
+impl<K, T, F> Schedule for Keyed<K, T, F>
+where
+    T: Send + 'static,
+    K: Hash + Eq + Clone + Send + 'static,
+    F: Fn(&T) -> K + Send + 'static,
+{
+    type Item = T;
+    type Output = T;
+    type Value = T;
+
+    fn receive(
+        &mut self,
+        value: T,
+        now: Instant,
+        discard: &Discard<T>,
+        counters: &Counters,
+    ) -> Result<(), T> {
+        let key = (self.key_of)(&value);
+        let is_new = !self.keys.states.contains_key(&key);
+
+        if is_new
+            && self
+                .limits
+                .max_keys
+                .is_some_and(|max_keys| self.keys.states.len() >= max_keys)
+        {
+            if let Some(oldest) = self.keys.least_recent().cloned() {
+                let evicted = self.keys.evict(&oldest);
+                Counters::increment(&counters.evicted_over_capacity);
+                release_evicted(
+                    evicted,
+                    self.limits.eviction_policy,
+                    &mut self.flushed,
+                    discard,
+                    counters,
+                );
+            }
+        }
+
+        let state = self.keys.touch(&key, now);
+
+        if now >= state.ready_at {
+            if !self.leading || (is_new && self.first_value == FirstValue::Delayed) {
+                // Open a window and hold the value back until it ends
+                state.ready_at = now + self.delay;
+            }
+
+            if let Some(superseded) = state.pending.replace(value) {
+                Counters::increment(&counters.superseded);
+                discard.discard(superseded, DiscardReason::Superseded);
+            }
+            let ready_at = state.ready_at.max(now);
+            self.keys.schedule(&key, ready_at);
+        } else if self.trailing {
+            // Inside the key's window, keep only the most recent value
+            if let Some(superseded) = state.pending.replace(value) {
+                Counters::increment(&counters.superseded);
+                discard.discard(superseded, DiscardReason::Superseded);
+            }
+            let ready_at = state.ready_at;
+            self.keys.schedule(&key, ready_at);
+        } else {
+            // The window has no trailing edge, so the value is dropped
+            Counters::increment(&counters.expired);
+            discard.discard(value, DiscardReason::Expired);
+        }
+        self.keys.update_idle(&key);
+
+        Ok(())
+    }
+
+    /// Forgets idle keys, then queues the values that have become due.
+    fn advance(&mut self, now: Instant, counters: &Counters) {
+        if let Some(idle_timeout) = self.limits.idle_timeout {
+            while let Some((key, idle_since)) = self.keys.longest_idle() {
+                if now < idle_since + idle_timeout {
+                    break;
+                }
+
+                // An idle key has nothing pending to flush or drop
+                let key = key.clone();
+                self.keys.evict(&key);
+                Counters::increment(&counters.evicted_idle);
+            }
+        }
+
+        self.keys.queue_due(now);
+    }
+
+    fn take_due(&mut self, now: Instant) -> Option<T> {
+        if now < self.global_ready_at {
+            return None;
+        }
+
+        // Values of evicted keys have waited longest, and have no key to
+        // take a turn with
+        if let Some(value) = self.flushed.pop_front() {
+            self.spent(now);
+            return Some(value);
+        }
+
+        let (key, value) = self.keys.take_ready()?;
+        self.sent(&key, now);
+        Some(value)
+    }
+
+    fn wake_at(&self, can_send: bool) -> Option<Instant> {
+        let idle_at = self
+            .limits
+            .idle_timeout
+            .and_then(|idle_timeout| Some(self.keys.longest_idle()?.1 + idle_timeout));
+        let due_at = self.keys.next_due().filter(|_| can_send);
+        let ready = !self.keys.ready.is_empty() || !self.flushed.is_empty();
+        let budget_at = (can_send && ready).then_some(self.global_ready_at);
+
+        [due_at, idle_at, budget_at].into_iter().flatten().min()
+    }
+
+    fn set_delay(&mut self, new_delay: Duration, now: Instant) {
+        self.keys.change_delay(self.delay, new_delay, now);
+        self.delay = new_delay;
+    }
+
+    /// Every key's pending value goes out, after those of evicted keys.
+    fn flush(&mut self, reset_window: bool, now: Instant, outbox: &mut Outbox<T>) {
+        if !self.flushed.is_empty() && reset_window {
+            self.spent(now);
+        }
+        outbox.extend(self.flushed.drain(..));
+
+        for (key, value) in self.keys.take_all() {
+            outbox.push_back(value);
+
+            if reset_window {
+                self.sent(&key, now);
+            } else {
+                self.keys.update_idle(&key);
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.flushed.is_empty() && self.keys.peek().is_none()
+    }
+
+    /// When the next pending value is due, or when the global budget allows
+    /// one if none is pending.
+    fn next_emit_at(&self) -> Instant {
+        let next_due = self
+            .keys
+            .next_due()
+            .filter(|_| self.keys.ready.is_empty() && self.flushed.is_empty());
+        next_due.map_or(self.global_ready_at, |due_at| {
+            due_at.max(self.global_ready_at)
+        })
+    }
+
+    fn peek(&self) -> Option<&T> {
+        self.flushed.front().or_else(|| self.keys.peek())
+    }
+
+    fn value(output: &T) -> &T {
+        output
+    }
+
+    fn drain(&mut self) -> Vec<T> {
+        let pending = self.keys.take_all().into_iter().map(|(_, value)| value);
+        self.flushed.drain(..).chain(pending).collect()
+    }
+
+    fn items(output: T) -> Vec<T> {
+        vec![output]
+    }
+
+    /// Values of evicted keys and those waiting in the ready queue go first,
+    /// then the rest in the order they are due, keeping to the global budget.
+    fn take_on_close(&mut self, now: Instant) -> Option<(Instant, T)> {
+        self.keys.queue_due(now);
+        let ready = self
+            .flushed
+            .pop_front()
+            .or_else(|| self.keys.take_ready().map(|(_, value)| value));
+        let (due_at, value) = match ready {
+            Some(value) => (now, value),
+            None => self.keys.take_scheduled()?,
+        };
+
+        let send_at = due_at.max(self.global_ready_at);
+        if let Some(global_delay) = self.limits.global_delay {
+            self.global_ready_at = send_at.max(now) + global_delay;
+        }
+
+        Some((send_at, value))
+    }
+}
+
+/// Queues or discards an evicted key's pending value according to `policy`.
+fn release_evicted<T>(
+    value: Option<T>,
+    policy: EvictionPolicy,
+    flushed: &mut VecDeque<T>,
+    discard: &Discard<T>,
+    counters: &Counters,
+) {
+    let Some(value) = value else {
+        return;
+    };
+
+    match policy {
+        EvictionPolicy::FlushPending => {
+            Counters::increment(&counters.evicted_pending_flushed);
+            flushed.push_back(value);
+        }
+        EvictionPolicy::DropPending => {
+            Counters::increment(&counters.evicted_pending_dropped);
+            discard.discard(value, DiscardReason::Evicted);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ClosePolicy, EvictionPolicy, Evictions, RateLimitedChannelBuilder};
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+    use tokio::time::{sleep, Instant};
+
+    /// Sends a, b, a, c, d to a channel tracking at most two keys, so c evicts
+    /// b and d evicts a while a has a value pending.
+    async fn lru_eviction(
+        eviction_policy: EvictionPolicy,
+    ) -> (Vec<(&'static str, i32)>, Evictions) {
+        let (input_tx, input_rx) = mpsc::channel::<(&str, i32)>(10);
+        let (mut output_rx, handle) = RateLimitedChannelBuilder::new(Duration::from_secs(1))
+            .max_keys(2)
+            .eviction_policy(eviction_policy)
+            .build_keyed(input_rx, |(key, _)| *key);
+
+        for value in [("a", 1), ("b", 1), ("a", 2), ("c", 1), ("d", 1)] {
+            input_tx.send(value).await.unwrap();
+        }
+        drop(input_tx);
+
+        let mut values = Vec::new();
+        while let Some(value) = output_rx.recv().await {
+            values.push(value);
+        }
+
+        (values, handle.evictions())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lru_eviction_drops_pending() {
+        let (values, evictions) = lru_eviction(EvictionPolicy::DropPending).await;
+
+        assert_eq!(values, vec![("a", 1), ("b", 1), ("c", 1), ("d", 1)]);
+        assert_eq!(
+            evictions,
+            Evictions {
+                over_capacity: 2,
+                pending_dropped: 1,
+                ..Evictions::default()
+            }
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lru_eviction_flushes_pending() {
+        let (values, evictions) = lru_eviction(EvictionPolicy::FlushPending).await;
+
+        assert_eq!(
+            values,
+            vec![("a", 1), ("b", 1), ("c", 1), ("a", 2), ("d", 1)]
+        );
+        assert_eq!(
+            evictions,
+            Evictions {
+                over_capacity: 2,
+                pending_flushed: 1,
+                ..Evictions::default()
+            }
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_key_is_forgotten() {
+        let (input_tx, input_rx) = mpsc::channel::<(&str, i32)>(10);
+        let (mut output_rx, handle) = RateLimitedChannelBuilder::new(Duration::from_secs(10))
+            .key_idle_timeout(Duration::from_secs(1))
+            .build_keyed(input_rx, |(key, _)| *key);
+
+        let start = Instant::now();
+        input_tx.send(("a", 1)).await.unwrap();
+        input_tx.send(("a", 2)).await.unwrap();
+        assert_eq!(output_rx.recv().await, Some(("a", 1)));
+
+        // A key with a value pending or a window open isn't idle
+        sleep(Duration::from_secs(2)).await;
+        assert_eq!(handle.evictions(), Evictions::default());
+        assert_eq!(output_rx.recv().await, Some(("a", 2)));
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+
+        sleep(Duration::from_millis(10500)).await;
+        assert_eq!(handle.evictions(), Evictions::default());
+        sleep(Duration::from_secs(1)).await;
+        assert_eq!(
+            handle.evictions(),
+            Evictions {
+                idle: 1,
+                ..Evictions::default()
+            }
+        );
+
+        input_tx.send(("a", 3)).await.unwrap();
+        assert_eq!(output_rx.recv().await, Some(("a", 3)));
+        assert_eq!(start.elapsed(), Duration::from_millis(21500));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keys_are_limited_independently() {
+        let (input_tx, input_rx) = mpsc::channel::<(&str, i32)>(10);
+        let (mut output_rx, _handle) = RateLimitedChannelBuilder::new(Duration::from_secs(1))
+            .build_keyed(input_rx, |(key, _)| *key);
+
+        let start = Instant::now();
+        for value in [("a", 1), ("a", 2), ("b", 1), ("a", 3)] {
+            input_tx.send(value).await.unwrap();
+        }
+
+        let mut values = Vec::new();
+        for _ in 0..3 {
+            let value = output_rx.recv().await.unwrap();
+            values.push((value, start.elapsed().as_millis()));
+        }
+
+        // The chatty key doesn't hold back the quiet one
+        assert_eq!(values, vec![(("a", 1), 0), (("b", 1), 0), (("a", 3), 1000)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keyed_close_policy() {
+        let (input_tx, input_rx) = mpsc::channel::<(&str, i32)>(10);
+        let (mut output_rx, _handle) = RateLimitedChannelBuilder::new(Duration::from_secs(1))
+            .close_policy(ClosePolicy::EmitAfterDelay)
+            .build_keyed(input_rx, |(key, _)| *key);
+
+        let start = Instant::now();
+        for value in [("a", 1), ("b", 1), ("a", 2), ("b", 2)] {
+            input_tx.send(value).await.unwrap();
+        }
+        drop(input_tx);
+
+        let mut values = Vec::new();
+        while let Some(value) = output_rx.recv().await {
+            values.push((value, start.elapsed().as_millis()));
+        }
+
+        assert_eq!(
+            values,
+            vec![
+                (("a", 1), 0),
+                (("b", 1), 0),
+                (("a", 2), 1000),
+                (("b", 2), 1000)
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_global_delay_round_robin() {
+        let (input_tx, input_rx) = mpsc::channel::<(&str, i32)>(10);
+        let (mut output_rx, _handle) = RateLimitedChannelBuilder::new(Duration::from_millis(100))
+            .global_delay(Duration::from_secs(1))
+            .build_keyed(input_rx, |(key, _)| *key);
+
+        let start = Instant::now();
+        for value in [("a", 1), ("a", 2), ("b", 1), ("c", 1)] {
+            input_tx.send(value).await.unwrap();
+        }
+        assert_eq!(output_rx.recv().await, Some(("a", 1)));
+
+        sleep(Duration::from_millis(500)).await;
+        input_tx.send(("a", 3)).await.unwrap();
+
+        let mut values = Vec::new();
+        for _ in 0..3 {
+            let value = output_rx.recv().await.unwrap();
+            values.push((value, start.elapsed().as_millis()));
+        }
+
+        // a's second value became due after b and c were already waiting
+        assert_eq!(
+            values,
+            vec![(("b", 1), 1000), (("c", 1), 2000), (("a", 3), 3000)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_global_delay_applies_to_evicted_keys() {
+        let (input_tx, input_rx) = mpsc::channel::<(&str, i32)>(10);
+        let (mut output_rx, handle) = RateLimitedChannelBuilder::new(Duration::from_secs(1))
+            .max_keys(1)
+            .global_delay(Duration::from_secs(10))
+            .build_keyed(input_rx, |(key, _)| *key);
+
+        let start = Instant::now();
+        for value in [("a", 1), ("a", 2), ("b", 1), ("c", 1), ("d", 1)] {
+            input_tx.send(value).await.unwrap();
+        }
+
+        let mut values = Vec::new();
+        for _ in 0..5 {
+            let value = output_rx.recv().await.unwrap();
+            values.push((value, start.elapsed().as_secs()));
+        }
+
+        // Every new key evicts the last one, flushing its pending value, but
+        // only one value goes out per global delay
+        assert_eq!(
+            values,
+            vec![
+                (("a", 1), 0),
+                (("a", 2), 10),
+                (("b", 1), 20),
+                (("c", 1), 30),
+                (("d", 1), 40)
+            ]
+        );
+        assert_eq!(handle.evictions().pending_flushed, 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_set_delay_moves_every_key() {
+        let (input_tx, input_rx) = mpsc::channel::<(&str, i32)>(10);
+        let (mut output_rx, handle) = RateLimitedChannelBuilder::new(Duration::from_secs(10))
+            .build_keyed(input_rx, |(key, _)| *key);
+
+        let start = Instant::now();
+        for value in [("a", 1), ("b", 1), ("a", 2), ("b", 2)] {
+            input_tx.send(value).await.unwrap();
+        }
+        assert_eq!(output_rx.recv().await, Some(("a", 1)));
+        assert_eq!(output_rx.recv().await, Some(("b", 1)));
+
+        handle.set_delay(Duration::from_secs(1));
+
+        assert_eq!(output_rx.recv().await, Some(("a", 2)));
+        assert_eq!(output_rx.recv().await, Some(("b", 2)));
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+    }
+}
@@ -1,6 +1,51 @@
 use std::time::Duration;
-use tokio::sync::mpsc::{self, Receiver, Sender};
-use tokio::time::{sleep, Instant};
+use tokio::sync::mpsc::Receiver;
+
+mod builder;
+mod discard;
+mod emitted;
+mod ext;
+mod handle;
+mod keyed;
+mod pending;
+mod poll;
+mod stats;
+#[cfg(feature = "stream")]
+mod stream;
+mod worker;
+
+pub use builder::{
+    EmissionMode, EvictionPolicy, FirstValue, OverflowPolicy, RateLimitedChannelBuilder,
+    DEFAULT_OUTPUT_CAPACITY,
+};
+pub use discard::{DiscardCallback, DiscardReason, OnDiscard};
+pub use emitted::Emitted;
+#[cfg(feature = "stream")]
+pub use ext::RateLimitStreamExt;
+pub use ext::ReceiverExt;
+pub use handle::{CompletionReason, RateLimiterHandle, Snapshot};
+pub use poll::RateLimitedReceiver;
+pub use stats::{Evictions, Stats};
+#[cfg(feature = "stream")]
+pub use stream::RateLimitedStream;
+
+/// What the worker does with a value that is still waiting for the delay to
+/// elapse when the input channel closes.
+///
+/// For a lossless channel this applies to every value still in the queue, and
+/// the default is [`EmitAfterDelay`](ClosePolicy::EmitAfterDelay) rather than
+/// [`DropPending`](ClosePolicy::DropPending).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClosePolicy {
+    /// Discard the pending value.
+    #[default]
+    DropPending,
+    /// Send the pending value right away, ignoring the remaining delay.
+    EmitImmediately,
+    /// Send the pending value once the remaining delay has elapsed, followed by
+    /// any other queued values at the usual rate.
+    EmitAfterDelay,
+}
 
 /// Creates a rate-limited channel from an input channel.
 ///
@@ -8,6 +53,9 @@ use tokio::time::{sleep, Instant};
 /// that will only send a value every specified delay duration. The value sent will be the
 /// most recent value received from the input channel at the time the duration expired.
 ///
+/// A value that is still pending when the input channel closes is dropped. Use
+/// [`to_rate_limited_channel_with_close_policy`] to keep it instead.
+///
 /// # Arguments
 ///
 /// * `input` - The receiver part of an input channel
@@ -16,82 +64,72 @@ use tokio::time::{sleep, Instant};
 /// # Returns
 ///
 /// A receiver that outputs values from the input channel at the specified rate
-pub fn to_rate_limited_channel<T: Clone + Send + 'static>(
+pub fn to_rate_limited_channel<T: Send + 'static>(
     input: Receiver<T>,
     delay: Duration,
 ) -> Receiver<T> {
-    let (output_tx, output_rx) = mpsc::channel::<T>(100);
-    
-    tokio::spawn(async move {
-        rate_limit_worker(input, output_tx, delay).await;
-    });
+    to_rate_limited_channel_with_close_policy(input, delay, ClosePolicy::default())
+}
+
+/// Creates a rate-limited channel from an input channel, using `close_policy` to
+/// decide what happens to a pending value when the input channel closes.
+///
+/// # Arguments
+///
+/// * `input` - The receiver part of an input channel
+/// * `delay` - The minimum duration between each value sent on the output channel
+/// * `close_policy` - What to do with a value that is still waiting to be sent when `input` closes
+///
+/// # Returns
+///
+/// A receiver that outputs values from the input channel at the specified rate
+///
+/// Further options, and a [`RateLimiterHandle`] for controlling the worker, are
+/// available through [`RateLimitedChannelBuilder`].
+pub fn to_rate_limited_channel_with_close_policy<T: Send + 'static>(
+    input: Receiver<T>,
+    delay: Duration,
+    close_policy: ClosePolicy,
+) -> Receiver<T> {
+    let (output_rx, _handle) = RateLimitedChannelBuilder::new(delay)
+        .close_policy(close_policy)
+        .build(input);
     
     output_rx
 }
 
-/// Worker function that processes the input channel and sends to the output channel
-/// at the specified rate.
-async fn rate_limit_worker<T: Clone + Send>(
-    mut input: Receiver<T>,
-    output: Sender<T>, 
-    delay: Duration
-) {
-    let mut last_send_time = Instant::now() - delay; // Allow immediate first send
+/// Creates a rate-limited channel from an input channel that combines values
+/// instead of keeping only the most recent one.
+///
+/// Every value received while another is waiting to be sent is folded into it
+/// with `merge(pending, new)`, so each emitted value accounts for everything
+/// received since the previous one. This suits counters, partial updates and
+/// set unions.
+///
+/// # Arguments
+///
+/// * `input` - The receiver part of an input channel
+/// * `delay` - The minimum duration between each value sent on the output channel
+/// * `merge` - Combines the pending value with a newly received one
+///
+/// # Returns
+///
+/// A receiver that outputs the merged values at the specified rate
+pub fn to_rate_limited_channel_with<T, F>(input: Receiver<T>, delay: Duration, merge: F) -> Receiver<T>
+where
+    T: Send + 'static,
+    F: FnMut(T, T) -> T + Send + 'static,
+{
+    let (output_rx, _handle) = RateLimitedChannelBuilder::new(delay).build_with_merge(input, merge);
     
-    while let Some(mut value) = input.recv().await {
-        // Keep accepting values while waiting for the delay to elapse
-        let mut received_additional_value = true;
-        
-        while received_additional_value {
-            // Check if we should send now (enough time has passed)
-            let now = Instant::now();
-            let time_since_last_send = now.duration_since(last_send_time);
-            
-            if time_since_last_send >= delay {
-                // Delay elapsed, send the current value
-                if let Err(_) = output.send(value.clone()).await {
-                    // Output channel closed, exit worker
-                    return;
-                }
-                
-                last_send_time = now;
-                break;
-            }
-            
-            // Calculate remaining time to wait
-            let wait_time = delay.saturating_sub(time_since_last_send);
-            
-            // Wait for either new value or timeout
-            received_additional_value = false;
-            tokio::select! {
-                // Either wait for a new message...
-                new_value = input.recv() => {
-                    match new_value {
-                        Some(v) => {
-                            // Got a new value, use it instead
-                            value = v;
-                            received_additional_value = true;
-                        },
-                        None => {
-                            // Input channel closed
-                            return;
-                        }
-                    }
-                },
-                // ...or wait for the delay time
-                _ = sleep(wait_time) => {
-                    // Time's up, ready to send in the next loop iteration
-                }
-            }
-        }
-    }
+    output_rx
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use tokio::sync::mpsc;
-    use tokio::time::{sleep, Duration};
+    use tokio::sync::{mpsc, oneshot};
+    use tokio::time::{sleep, Duration, Instant};
     
     #[tokio::test]
     async fn test_rate_limited_channel_once_per_second() {
@@ -112,7 +150,7 @@ mod tests {
             let end_time = Instant::now() + TEST_DURATION;
             
             while Instant::now() < end_time {
-                if let Err(_) = input_tx.send(number_of_values_written).await {
+                if input_tx.send(number_of_values_written).await.is_err() {
                     break;
                 }
                 number_of_values_written += 1;
@@ -172,7 +210,7 @@ mod tests {
             let end_time = Instant::now() + TEST_DURATION;
             
             while Instant::now() < end_time {
-                if let Err(_) = input_tx.send(number_of_values_written).await {
+                if input_tx.send(number_of_values_written).await.is_err() {
                     break;
                 }
                 number_of_values_written += 1;
@@ -228,7 +266,7 @@ mod tests {
             let end_time = Instant::now() + TEST_DURATION;
             
             while Instant::now() < end_time {
-                if let Err(_) = input_tx.send(number_of_values_written).await {
+                if input_tx.send(number_of_values_written).await.is_err() {
                     break;
                 }
                 number_of_values_written += 1;
@@ -263,6 +301,96 @@ mod tests {
         
         // With a 10-second rate limit and a 12-second test, we should see at least 1 value
         // The first value should be emitted almost immediately, and the second around the 10-second mark
-        assert!(values.len() >= 1);
+        assert!(!values.is_empty());
+    }
+    
+    /// Sends two values back to back and closes the input, returning the output
+    /// values along with how long after the start each one arrived.
+    async fn close_with_pending_value(close_policy: ClosePolicy) -> Vec<(i32, Duration)> {
+        const RATE_LIMIT: Duration = Duration::from_secs(1);
+        
+        let (input_tx, input_rx) = mpsc::channel::<i32>(10);
+        let mut output_rx = to_rate_limited_channel_with_close_policy(input_rx, RATE_LIMIT, close_policy);
+        
+        let start = Instant::now();
+        input_tx.send(1).await.unwrap();
+        input_tx.send(2).await.unwrap();
+        drop(input_tx);
+        
+        let mut values = Vec::new();
+        while let Some(value) = output_rx.recv().await {
+            values.push((value, start.elapsed()));
+        }
+        
+        values
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn test_close_policy_drop_pending() {
+        let values = close_with_pending_value(ClosePolicy::DropPending).await;
+        
+        assert_eq!(values, vec![(1, Duration::ZERO)]);
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn test_close_policy_emit_immediately() {
+        let values = close_with_pending_value(ClosePolicy::EmitImmediately).await;
+        
+        assert_eq!(values, vec![(1, Duration::ZERO), (2, Duration::ZERO)]);
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn test_close_policy_emit_after_delay() {
+        let values = close_with_pending_value(ClosePolicy::EmitAfterDelay).await;
+        
+        assert_eq!(values, vec![(1, Duration::ZERO), (2, Duration::from_secs(1))]);
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn test_pending_value_sent_when_input_goes_quiet() {
+        const RATE_LIMIT: Duration = Duration::from_secs(1);
+        
+        let (input_tx, input_rx) = mpsc::channel::<i32>(10);
+        let mut output_rx = to_rate_limited_channel(input_rx, RATE_LIMIT);
+        
+        let start = Instant::now();
+        input_tx.send(1).await.unwrap();
+        input_tx.send(2).await.unwrap();
+        
+        // The input stays open, so the pending value must go out when the delay expires
+        assert_eq!(output_rx.recv().await, Some(1));
+        assert_eq!(output_rx.recv().await, Some(2));
+        assert_eq!(start.elapsed(), RATE_LIMIT);
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn test_non_clone_values() {
+        // oneshot senders can't be cloned, so they have to be moved through the worker
+        let (input_tx, input_rx) = mpsc::channel::<oneshot::Sender<i32>>(10);
+        let mut output_rx = to_rate_limited_channel(input_rx, Duration::from_secs(1));
+        
+        let (reply_tx, reply_rx) = oneshot::channel();
+        input_tx.send(reply_tx).await.unwrap();
+        
+        output_rx.recv().await.unwrap().send(42).unwrap();
+        assert_eq!(reply_rx.await, Ok(42));
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn test_merge_values_within_window() {
+        const RATE_LIMIT: Duration = Duration::from_secs(1);
+        
+        let (input_tx, input_rx) = mpsc::channel::<u32>(10);
+        let mut output_rx = to_rate_limited_channel_with(input_rx, RATE_LIMIT, |a, b| a + b);
+        
+        let start = Instant::now();
+        for value in 1..=5 {
+            input_tx.send(value).await.unwrap();
+        }
+        
+        // The first value goes out straight away, the rest are summed
+        assert_eq!(output_rx.recv().await, Some(1));
+        assert_eq!(output_rx.recv().await, Some(14));
+        assert_eq!(start.elapsed(), RATE_LIMIT);
     }
 }
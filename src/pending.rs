@@ -0,0 +1,520 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::OverflowPolicy;
+
+/// What happened to a value given to [`Pending::push`].
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Pushed<T> {
+    /// Stored alongside the values already pending.
+    Stored,
+    /// Folded into the pending value.
+    Merged,
+    /// Stored in place of an older value, which is handed back.
+    Replaced(T),
+    /// Not stored, so the value is handed back.
+    Rejected(T),
+}
+
+/// Values the worker is holding on to until it is allowed to send.
+///
+/// Each implementation decides how a newly received value is combined with
+/// the ones already waiting, and what gets sent once the worker may emit.
+pub(crate) trait Pending {
+    /// The type received on the input channel.
+    type Item;
+    /// The type sent on the output channel.
+    type Output;
+    /// The type of a pending value, without anything that is only added when
+    /// it is taken.
+    type Value;
+
+    fn is_empty(&self) -> bool;
+
+    /// Returns `true` if every value is kept rather than combined.
+    fn is_lossless(&self) -> bool {
+        false
+    }
+
+    /// Returns `true` if no more values can be accepted until one is sent.
+    fn is_full(&self) -> bool {
+        false
+    }
+
+    /// The earliest time the pending values should be sent, if they would
+    /// rather wait for more input than go out as soon as the rate allows.
+    fn hold_until(&self) -> Option<Instant> {
+        None
+    }
+
+    /// Stores a value, and reports any value that was discarded as a result.
+    ///
+    /// Returns the value back if it can't be stored and the worker should stop.
+    fn push(&mut self, value: Self::Item) -> Result<Pushed<Self::Item>, Self::Item>;
+
+    /// Removes the next value to send.
+    fn take(&mut self) -> Option<Self::Output>;
+
+    /// Removes every pending value, as the values that were pushed or merged.
+    fn drain(&mut self) -> Vec<Self::Item>;
+
+    /// Splits a value that was taken but couldn't be sent back into the
+    /// values that were pushed or merged.
+    fn items(output: Self::Output) -> Vec<Self::Item>;
+
+    /// The pending value a value returned by [`take`](Self::take) holds.
+    fn value(output: &Self::Output) -> &Self::Value;
+
+    /// Finishes a value returned by [`take`](Self::take) as it is sent, which
+    /// may be a while later if the output channel is full.
+    fn sending(_output: &mut Self::Output) {}
+
+    /// The next value to send, as [`take`](Self::take) would return it, without
+    /// removing it.
+    fn peek(&self) -> Option<&Self::Value>;
+}
+
+/// Keeps only the most recent value.
+pub(crate) struct Latest<T>(Option<T>);
+
+impl<T> Latest<T> {
+    pub(crate) fn new() -> Self {
+        Latest(None)
+    }
+}
+
+impl<T> Pending for Latest<T> {
+    type Item = T;
+    type Output = T;
+    type Value = T;
+
+    fn is_empty(&self) -> bool {
+        self.0.is_none()
+    }
+
+    fn push(&mut self, value: T) -> Result<Pushed<T>, T> {
+        Ok(match self.0.replace(value) {
+            Some(previous) => Pushed::Replaced(previous),
+            None => Pushed::Stored,
+        })
+    }
+
+    fn take(&mut self) -> Option<T> {
+        self.0.take()
+    }
+
+    fn drain(&mut self) -> Vec<T> {
+        self.0.take().into_iter().collect()
+    }
+
+    fn items(output: T) -> Vec<T> {
+        vec![output]
+    }
+
+    fn value(output: &T) -> &T {
+        output
+    }
+
+    fn peek(&self) -> Option<&T> {
+        self.0.as_ref()
+    }
+}
+
+/// Folds new values into the pending one.
+pub(crate) struct Merge<T, F> {
+    value: Option<T>,
+    merge: F,
+}
+
+impl<T, F> Merge<T, F> {
+    pub(crate) fn new(merge: F) -> Self {
+        Merge { value: None, merge }
+    }
+}
+
+impl<T, F> Pending for Merge<T, F>
+where
+    T: Send + 'static,
+    F: FnMut(T, T) -> T + Send + 'static,
+{
+    type Item = T;
+    type Output = T;
+    type Value = T;
+
+    fn is_empty(&self) -> bool {
+        self.value.is_none()
+    }
+
+    fn push(&mut self, value: T) -> Result<Pushed<T>, T> {
+        let (value, pushed) = match self.value.take() {
+            Some(previous) => ((self.merge)(previous, value), Pushed::Merged),
+            None => (value, Pushed::Stored),
+        };
+        self.value = Some(value);
+        Ok(pushed)
+    }
+
+    fn take(&mut self) -> Option<T> {
+        self.value.take()
+    }
+
+    fn drain(&mut self) -> Vec<T> {
+        self.value.take().into_iter().collect()
+    }
+
+    fn items(output: T) -> Vec<T> {
+        vec![output]
+    }
+
+    fn value(output: &T) -> &T {
+        output
+    }
+
+    fn peek(&self) -> Option<&T> {
+        self.value.as_ref()
+    }
+}
+
+/// Keeps every value and sends them one at a time in the order they arrived.
+pub(crate) struct Queue<T> {
+    values: VecDeque<T>,
+    capacity: usize,
+    overflow: OverflowPolicy,
+}
+
+impl<T> Queue<T> {
+    pub(crate) fn new(capacity: usize, overflow: OverflowPolicy) -> Self {
+        Queue {
+            values: VecDeque::with_capacity(capacity),
+            capacity,
+            overflow,
+        }
+    }
+}
+
+impl<T: Send + 'static> Pending for Queue<T> {
+    type Item = T;
+    type Output = T;
+    type Value = T;
+
+    fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    fn is_lossless(&self) -> bool {
+        true
+    }
+
+    /// Only a queue using [`OverflowPolicy::Block`] is ever full; the other
+    /// policies make room by dropping values instead.
+    fn is_full(&self) -> bool {
+        self.overflow == OverflowPolicy::Block && self.values.len() >= self.capacity
+    }
+
+    /// Makes room according to the overflow policy if needed. Returns the value
+    /// back if the queue is full and its policy is [`OverflowPolicy::Error`].
+    fn push(&mut self, value: T) -> Result<Pushed<T>, T> {
+        let mut pushed = Pushed::Stored;
+
+        if self.values.len() >= self.capacity {
+            match self.overflow {
+                // The worker stops reading input before the queue fills up
+                OverflowPolicy::Block => {}
+                OverflowPolicy::DropOldest => {
+                    if let Some(oldest) = self.values.pop_front() {
+                        pushed = Pushed::Replaced(oldest);
+                    }
+                }
+                OverflowPolicy::DropNewest => return Ok(Pushed::Rejected(value)),
+                OverflowPolicy::Error => return Err(value),
+            }
+        }
+
+        self.values.push_back(value);
+        Ok(pushed)
+    }
+
+    fn take(&mut self) -> Option<T> {
+        self.values.pop_front()
+    }
+
+    fn drain(&mut self) -> Vec<T> {
+        self.values.drain(..).collect()
+    }
+
+    fn items(output: T) -> Vec<T> {
+        vec![output]
+    }
+
+    fn value(output: &T) -> &T {
+        output
+    }
+
+    fn peek(&self) -> Option<&T> {
+        self.values.front()
+    }
+}
+
+/// When a batch counts as complete.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct BatchLimits {
+    /// The most values a batch can hold.
+    pub(crate) max_size: Option<usize>,
+    /// The total weight at which a batch is full.
+    pub(crate) max_weight: Option<usize>,
+    /// How long a batch that isn't full waits after its first value.
+    pub(crate) max_delay: Option<Duration>,
+}
+
+/// Collects every value and sends them together.
+pub(crate) struct Batch<T, W> {
+    values: Vec<T>,
+    limits: BatchLimits,
+    weigh: W,
+    weight: usize,
+    started_at: Option<Instant>,
+}
+
+impl<T, W> Batch<T, W> {
+    /// `weigh` gives the weight of each value, counted against
+    /// [`BatchLimits::max_weight`].
+    pub(crate) fn new(limits: BatchLimits, weigh: W) -> Self {
+        Batch {
+            values: Vec::new(),
+            limits,
+            weigh,
+            weight: 0,
+            started_at: None,
+        }
+    }
+}
+
+impl<T, W> Pending for Batch<T, W>
+where
+    T: Send + 'static,
+    W: FnMut(&T) -> usize + Send + 'static,
+{
+    type Item = T;
+    type Output = Vec<T>;
+    type Value = Vec<T>;
+
+    fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    fn is_lossless(&self) -> bool {
+        true
+    }
+
+    /// A full batch leaves further values in the input channel for the next
+    /// batch. The value that takes a batch past its maximum weight is still
+    /// part of it.
+    fn is_full(&self) -> bool {
+        self.limits
+            .max_size
+            .is_some_and(|max_size| self.values.len() >= max_size)
+            || self
+                .limits
+                .max_weight
+                .is_some_and(|max_weight| self.weight >= max_weight)
+    }
+
+    /// A batch that isn't full waits for more values until its maximum delay
+    /// has passed.
+    fn hold_until(&self) -> Option<Instant> {
+        if self.is_full() {
+            return None;
+        }
+
+        Some(self.started_at? + self.limits.max_delay?)
+    }
+
+    fn push(&mut self, value: T) -> Result<Pushed<T>, T> {
+        if self.values.is_empty() {
+            self.started_at = Some(Instant::now());
+        }
+
+        self.weight += (self.weigh)(&value);
+        self.values.push(value);
+        Ok(Pushed::Stored)
+    }
+
+    fn take(&mut self) -> Option<Vec<T>> {
+        if self.values.is_empty() {
+            return None;
+        }
+
+        self.weight = 0;
+        self.started_at = None;
+        Some(std::mem::take(&mut self.values))
+    }
+
+    fn drain(&mut self) -> Vec<T> {
+        self.take().unwrap_or_default()
+    }
+
+    fn items(output: Vec<T>) -> Vec<T> {
+        output
+    }
+
+    fn value(output: &Vec<T>) -> &Vec<T> {
+        output
+    }
+
+    fn peek(&self) -> Option<&Vec<T>> {
+        if self.values.is_empty() {
+            return None;
+        }
+
+        Some(&self.values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latest_keeps_most_recent() {
+        let mut pending = Latest::new();
+
+        assert_eq!(pending.push(1), Ok(Pushed::Stored));
+        assert_eq!(pending.push(2), Ok(Pushed::Replaced(1)));
+
+        assert_eq!(pending.take(), Some(2));
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn test_merge_folds_values() {
+        let mut pending = Merge::new(|a: Vec<i32>, b: Vec<i32>| [a, b].concat());
+
+        pending.push(vec![1]).unwrap();
+        pending.push(vec![2, 3]).unwrap();
+
+        assert_eq!(pending.take(), Some(vec![1, 2, 3]));
+        assert!(pending.is_empty());
+
+        pending.push(vec![4]).unwrap();
+        assert_eq!(pending.take(), Some(vec![4]));
+    }
+
+    #[test]
+    fn test_queue_drop_oldest() {
+        let mut pending = Queue::new(2, OverflowPolicy::DropOldest);
+
+        for value in 0..4 {
+            pending.push(value).unwrap();
+        }
+
+        assert_eq!(pending.take(), Some(2));
+        assert_eq!(pending.take(), Some(3));
+        assert_eq!(pending.take(), None);
+    }
+
+    #[test]
+    fn test_queue_drop_newest() {
+        let mut pending = Queue::new(2, OverflowPolicy::DropNewest);
+
+        for value in 0..4 {
+            pending.push(value).unwrap();
+        }
+        assert_eq!(pending.push(4), Ok(Pushed::Rejected(4)));
+
+        assert_eq!(pending.take(), Some(0));
+        assert_eq!(pending.take(), Some(1));
+        assert_eq!(pending.take(), None);
+    }
+
+    #[test]
+    fn test_peek_matches_take() {
+        let mut pending = Queue::new(2, OverflowPolicy::Block);
+        assert!(pending.peek().is_none());
+
+        pending.push(0).unwrap();
+        pending.push(1).unwrap();
+        assert_eq!(pending.peek(), Some(&0));
+
+        let mut pending = Batch::new(BatchLimits::default(), |_: &i32| 0);
+        pending.push(0).unwrap();
+        pending.push(1).unwrap();
+        assert_eq!(pending.peek(), Some(&vec![0, 1]));
+        assert_eq!(pending.take(), Some(vec![0, 1]));
+    }
+
+    #[test]
+    fn test_queue_error_returns_value() {
+        let mut pending = Queue::new(1, OverflowPolicy::Error);
+
+        pending.push(0).unwrap();
+
+        assert_eq!(pending.push(1), Err(1));
+        assert_eq!(pending.take(), Some(0));
+    }
+
+    #[test]
+    fn test_queue_block_is_full() {
+        let mut pending = Queue::new(1, OverflowPolicy::Block);
+
+        assert!(!pending.is_full());
+        pending.push(0).unwrap();
+        assert!(pending.is_full());
+    }
+
+    #[test]
+    fn test_batch_collects_values() {
+        let limits = BatchLimits {
+            max_size: Some(2),
+            ..BatchLimits::default()
+        };
+        let mut pending = Batch::new(limits, |_: &i32| 0);
+
+        assert_eq!(pending.take(), None);
+        pending.push(0).unwrap();
+        assert!(!pending.is_full());
+        pending.push(1).unwrap();
+        assert!(pending.is_full());
+
+        assert_eq!(pending.take(), Some(vec![0, 1]));
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn test_batch_max_weight() {
+        let limits = BatchLimits {
+            max_weight: Some(10),
+            ..BatchLimits::default()
+        };
+        let mut pending = Batch::new(limits, |value: &&str| value.len());
+
+        pending.push("aaaa").unwrap();
+        pending.push("bbbb").unwrap();
+        assert!(!pending.is_full());
+        pending.push("ccc").unwrap();
+        assert!(pending.is_full());
+
+        assert_eq!(pending.take(), Some(vec!["aaaa", "bbbb", "ccc"]));
+        assert!(!pending.is_full());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_batch_hold_until() {
+        let limits = BatchLimits {
+            max_size: Some(2),
+            max_delay: Some(Duration::from_secs(1)),
+            ..BatchLimits::default()
+        };
+        let mut pending = Batch::new(limits, |_: &i32| 0);
+
+        assert_eq!(pending.hold_until(), None);
+
+        let start = Instant::now();
+        pending.push(0).unwrap();
+        assert_eq!(pending.hold_until(), Some(start + Duration::from_secs(1)));
+
+        // A full batch is ready straight away
+        pending.push(1).unwrap();
+        assert_eq!(pending.hold_until(), None);
+    }
+}
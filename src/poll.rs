@@ -0,0 +1,287 @@
+use std::fmt;
+use std::future::{poll_fn, Future};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::mpsc::Receiver;
+use tokio::time::{sleep_until, Instant, Sleep};
+
+use crate::discard::Discard;
+use crate::pending::Latest;
+use crate::stats::Counters;
+use crate::worker::{Paced, Schedule};
+use crate::{ClosePolicy, RateLimitedChannelBuilder};
+
+/// How many values are read from the input in one poll before giving other
+/// tasks a turn, so an input that is always ready can't starve them.
+const INPUT_BUDGET: usize = 32;
+
+/// Drives the worker's throttle schedule for the poll-driven limiters.
+///
+/// Instead of running in a worker, it is polled by the consumer together with
+/// a function that polls the input, and sleeps until the schedule has
+/// something to send.
+pub(crate) struct Limiter<T> {
+    schedule: Paced<Latest<T>>,
+    close_policy: ClosePolicy,
+    // Nothing reads these yet, but the schedule reports to them
+    discard: Discard<T>,
+    counters: Counters,
+    sleep: Option<Pin<Box<Sleep>>>,
+    input_done: bool,
+}
+
+// The pending value is never pinned, and the sleep is boxed
+impl<T> Unpin for Limiter<T> {}
+
+impl<T> fmt::Debug for Limiter<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Limiter")
+            .field("close_policy", &self.close_policy)
+            .field("pending", &!self.schedule.is_empty())
+            .field("input_done", &self.input_done)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T> Limiter<T> {
+    pub(crate) fn new(delay: Duration, close_policy: ClosePolicy) -> Self {
+        let config = RateLimitedChannelBuilder::new(delay)
+            .close_policy(close_policy)
+            .worker_config();
+
+        Self {
+            schedule: Paced::new(Latest::new(), &config),
+            close_policy,
+            discard: Discard::new(None),
+            counters: Counters::default(),
+            sleep: None,
+            input_done: false,
+        }
+    }
+
+    /// Bounds the number of values still to come, given the input's bounds.
+    #[cfg(feature = "stream")]
+    pub(crate) fn size_hint(&self, input: (usize, Option<usize>)) -> (usize, Option<usize>) {
+        let pending = usize::from(!self.schedule.is_empty());
+        let upper = if self.input_done { Some(0) } else { input.1 };
+
+        (0, upper.and_then(|upper| upper.checked_add(pending)))
+    }
+
+    pub(crate) fn poll_next(
+        &mut self,
+        cx: &mut Context<'_>,
+        mut poll_input: impl FnMut(&mut Context<'_>) -> Poll<Option<T>>,
+    ) -> Poll<Option<T>> {
+        // Read everything the input has ready, keeping only the latest value
+        let mut budget = INPUT_BUDGET;
+        while !self.input_done {
+            if budget == 0 {
+                cx.waker().wake_by_ref();
+                break;
+            }
+            budget -= 1;
+
+            match poll_input(cx) {
+                Poll::Ready(Some(value)) => {
+                    let now = Instant::now();
+                    // Only a full queue hands a value back, and the latest
+                    // value is never full
+                    let _ = self
+                        .schedule
+                        .receive(value, now, &self.discard, &self.counters);
+
+                    // No delay in progress, so the value goes out straight away
+                    if let Some(value) = self.schedule.take_due(now) {
+                        return Poll::Ready(Some(value));
+                    }
+                }
+                Poll::Ready(None) => self.input_done = true,
+                Poll::Pending => break,
+            }
+        }
+
+        if self.input_done {
+            match self.close_policy {
+                ClosePolicy::DropPending => {
+                    self.schedule.drain();
+                    return Poll::Ready(None);
+                }
+                ClosePolicy::EmitImmediately => {
+                    let value = self.schedule.take_on_close(Instant::now());
+                    return Poll::Ready(value.map(|(_, value)| value));
+                }
+                ClosePolicy::EmitAfterDelay if self.schedule.is_empty() => {
+                    return Poll::Ready(None)
+                }
+                ClosePolicy::EmitAfterDelay => {}
+            }
+        }
+
+        loop {
+            let now = Instant::now();
+            self.schedule.advance(now, &self.counters);
+            if let Some(value) = self.schedule.take_due(now) {
+                // The delay has elapsed, so the pending value goes out
+                return Poll::Ready(Some(value));
+            }
+
+            // Without a pending value the input wakes the task once it has another
+            let Some(wake_at) = self.schedule.wake_at(true) else {
+                return Poll::Pending;
+            };
+
+            let sleep = match &mut self.sleep {
+                Some(sleep) => {
+                    if sleep.deadline() != wake_at {
+                        sleep.as_mut().reset(wake_at);
+                    }
+                    sleep
+                }
+                None => self.sleep.insert(Box::pin(sleep_until(wake_at))),
+            };
+
+            if sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+/// A receiver that yields the most recent value of an input channel at most
+/// once per delay.
+///
+/// It behaves like the receiver returned by
+/// [`to_rate_limited_channel`](crate::to_rate_limited_channel), but the rate
+/// limiting happens while it is being received from: there is no background
+/// task and no intermediate channel, so it can be created outside a Tokio
+/// runtime and a panic can't go unnoticed in a detached worker.
+#[derive(Debug)]
+pub struct RateLimitedReceiver<T> {
+    input: Receiver<T>,
+    limiter: Limiter<T>,
+}
+
+impl<T> RateLimitedReceiver<T> {
+    /// Rate limits `input` to one value per `delay`.
+    ///
+    /// A value that is still pending when `input` closes is dropped. Use
+    /// [`with_close_policy`](Self::with_close_policy) to keep it instead.
+    pub fn new(input: Receiver<T>, delay: Duration) -> Self {
+        Self::with_close_policy(input, delay, ClosePolicy::default())
+    }
+
+    /// Rate limits `input` to one value per `delay`, using `close_policy` to
+    /// decide what happens to a pending value when `input` closes.
+    pub fn with_close_policy(
+        input: Receiver<T>,
+        delay: Duration,
+        close_policy: ClosePolicy,
+    ) -> Self {
+        Self {
+            input,
+            limiter: Limiter::new(delay, close_policy),
+        }
+    }
+
+    /// Receives the next rate-limited value, or `None` once the input channel
+    /// has closed and nothing is left to send.
+    pub async fn recv(&mut self) -> Option<T> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    /// Polls for the next rate-limited value.
+    ///
+    /// The task is woken when a value arrives on the input channel or the
+    /// pending value's delay elapses.
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let input = &mut self.input;
+        self.limiter.poll_next(cx, |cx| input.poll_recv(cx))
+    }
+
+    /// Consumes the rate limiter, returning the input channel. Any pending
+    /// value is dropped.
+    pub fn into_inner(self) -> Receiver<T> {
+        self.input
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_yields_latest_value_per_delay() {
+        let (input_tx, input_rx) = mpsc::channel::<i32>(10);
+        let mut rx = RateLimitedReceiver::new(input_rx, Duration::from_secs(1));
+
+        let start = Instant::now();
+        for value in [1, 2, 3] {
+            input_tx.send(value).await.unwrap();
+        }
+
+        assert_eq!(rx.recv().await, Some(1));
+        assert_eq!(rx.recv().await, Some(3));
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+
+        // After a quiet period the next value goes out straight away
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        input_tx.send(4).await.unwrap();
+        assert_eq!(rx.recv().await, Some(4));
+        assert_eq!(start.elapsed(), Duration::from_secs(6));
+
+        drop(input_tx);
+        assert_eq!(rx.recv().await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_close_policy() {
+        for (close_policy, expected) in [
+            (ClosePolicy::DropPending, vec![1]),
+            (ClosePolicy::EmitImmediately, vec![1, 3]),
+            (ClosePolicy::EmitAfterDelay, vec![1, 3]),
+        ] {
+            let (input_tx, input_rx) = mpsc::channel::<i32>(10);
+            let mut rx = RateLimitedReceiver::with_close_policy(
+                input_rx,
+                Duration::from_secs(1),
+                close_policy,
+            );
+            for value in [1, 2, 3] {
+                input_tx.send(value).await.unwrap();
+            }
+            drop(input_tx);
+
+            let start = Instant::now();
+            let mut received = Vec::new();
+            while let Some(value) = rx.recv().await {
+                received.push(value);
+            }
+
+            assert_eq!(received, expected, "{close_policy:?}");
+            let elapsed = if close_policy == ClosePolicy::EmitAfterDelay {
+                Duration::from_secs(1)
+            } else {
+                Duration::ZERO
+            };
+            assert_eq!(start.elapsed(), elapsed, "{close_policy:?}");
+        }
+    }
+
+    #[test]
+    fn test_created_outside_runtime() {
+        let (input_tx, input_rx) = mpsc::channel::<i32>(10);
+        let mut rx = RateLimitedReceiver::new(input_rx, Duration::from_millis(10));
+        input_tx.try_send(1).unwrap();
+        drop(input_tx);
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        assert_eq!(runtime.block_on(rx.recv()), Some(1));
+        assert_eq!(runtime.block_on(rx.recv()), None);
+    }
+}
@@ -0,0 +1,118 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::pending::Pushed;
+
+/// Counters a worker updates as it runs, read through its
+/// [`RateLimiterHandle`](crate::RateLimiterHandle).
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    pub(crate) received: AtomicU64,
+    pub(crate) superseded: AtomicU64,
+    pub(crate) expired: AtomicU64,
+    pub(crate) emitted: AtomicU64,
+    pub(crate) dropped_at_close: AtomicU64,
+    pub(crate) send_wait_nanos: AtomicU64,
+    pub(crate) evicted_over_capacity: AtomicU64,
+    pub(crate) evicted_idle: AtomicU64,
+    pub(crate) evicted_pending_flushed: AtomicU64,
+    pub(crate) evicted_pending_dropped: AtomicU64,
+}
+
+impl Counters {
+    pub(crate) fn increment(counter: &AtomicU64) {
+        Self::add(counter, 1);
+    }
+
+    pub(crate) fn add(counter: &AtomicU64, amount: u64) {
+        counter.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    /// Counts what happened to a value the worker received.
+    pub(crate) fn pushed<T>(&self, pushed: &Pushed<T>) {
+        match pushed {
+            Pushed::Stored => {}
+            Pushed::Merged | Pushed::Replaced(_) => Self::increment(&self.superseded),
+            Pushed::Rejected(_) => Self::increment(&self.expired),
+        }
+    }
+
+    /// Counts a value that was sent after waiting `waited` for room in the
+    /// output channel.
+    pub(crate) fn sent(&self, waited: Duration) {
+        self.waited(waited);
+        Self::increment(&self.emitted);
+    }
+
+    fn waited(&self, waited: Duration) {
+        Self::add(
+            &self.send_wait_nanos,
+            u64::try_from(waited.as_nanos()).unwrap_or(u64::MAX),
+        );
+    }
+
+    pub(crate) fn stats(&self) -> Stats {
+        Stats {
+            received: self.received.load(Ordering::Relaxed),
+            superseded: self.superseded.load(Ordering::Relaxed),
+            expired: self.expired.load(Ordering::Relaxed),
+            emitted: self.emitted.load(Ordering::Relaxed),
+            dropped_at_close: self.dropped_at_close.load(Ordering::Relaxed),
+            send_wait: Duration::from_nanos(self.send_wait_nanos.load(Ordering::Relaxed)),
+        }
+    }
+
+    pub(crate) fn evictions(&self) -> Evictions {
+        Evictions {
+            over_capacity: self.evicted_over_capacity.load(Ordering::Relaxed),
+            idle: self.evicted_idle.load(Ordering::Relaxed),
+            pending_flushed: self.evicted_pending_flushed.load(Ordering::Relaxed),
+            pending_dropped: self.evicted_pending_dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// What a worker has done with the values it received so far.
+///
+/// Each counter is read on its own, so a snapshot taken while the worker is
+/// running may be off by a value or two between counters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Values read from the input channel.
+    pub received: u64,
+    /// Values that were replaced by or merged into a newer one before they
+    /// could be sent, including the oldest values dropped from a full
+    /// [`OverflowPolicy::DropOldest`](crate::OverflowPolicy::DropOldest) queue.
+    pub superseded: u64,
+    /// Values discarded on arrival because they could no longer be sent:
+    /// inside a throttle window without a trailing edge, or while an
+    /// [`OverflowPolicy::DropNewest`](crate::OverflowPolicy::DropNewest) or
+    /// [`OverflowPolicy::Error`](crate::OverflowPolicy::Error) queue was full.
+    pub expired: u64,
+    /// Values sent on the output channel. A batch counts once.
+    pub emitted: u64,
+    /// Values still pending when the worker stopped that its
+    /// [`ClosePolicy`](crate::ClosePolicy) discarded, or that couldn't be sent
+    /// because the output receiver was dropped.
+    pub dropped_at_close: u64,
+    /// Total time spent waiting for room in the output channel.
+    pub send_wait: Duration,
+}
+
+/// How many keys a keyed channel has stopped tracking, and what happened to
+/// the values they still had pending.
+///
+/// Always zero for channels that aren't keyed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Evictions {
+    /// Keys evicted to make room for a new key once
+    /// [`max_keys`](crate::RateLimitedChannelBuilder::max_keys) were tracked.
+    pub over_capacity: u64,
+    /// Keys evicted after being idle for the
+    /// [`key_idle_timeout`](crate::RateLimitedChannelBuilder::key_idle_timeout).
+    pub idle: u64,
+    /// Pending values sent early because their key was evicted.
+    pub pending_flushed: u64,
+    /// Pending values discarded because their key was evicted.
+    pub pending_dropped: u64,
+}
@@ -0,0 +1,126 @@
+use futures_core::Stream;
+use pin_project_lite::pin_project;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::poll::{Limiter, RateLimitedReceiver};
+use crate::ClosePolicy;
+
+pin_project! {
+    /// A [`Stream`] that yields the most recent item of another stream at most
+    /// once per delay.
+    ///
+    /// This is the stream counterpart of [`to_rate_limited_channel`](crate::to_rate_limited_channel):
+    /// an item that arrives when no delay is in progress is yielded straight
+    /// away, and the latest item received during a delay is yielded once it
+    /// has elapsed. All the work happens while the stream is polled, so there
+    /// is no background task.
+    #[must_use = "streams do nothing unless polled"]
+    pub struct RateLimitedStream<S>
+    where
+        S: Stream,
+    {
+        #[pin]
+        input: S,
+        limiter: Limiter<S::Item>,
+    }
+}
+
+impl<S: Stream> RateLimitedStream<S> {
+    /// Rate limits `input` to one item per `delay`.
+    ///
+    /// An item that is still pending when `input` ends is dropped. Use
+    /// [`with_close_policy`](Self::with_close_policy) to keep it instead.
+    pub fn new(input: S, delay: Duration) -> Self {
+        Self::with_close_policy(input, delay, ClosePolicy::default())
+    }
+
+    /// Rate limits `input` to one item per `delay`, using `close_policy` to
+    /// decide what happens to a pending item when `input` ends.
+    pub fn with_close_policy(input: S, delay: Duration, close_policy: ClosePolicy) -> Self {
+        Self {
+            input,
+            limiter: Limiter::new(delay, close_policy),
+        }
+    }
+
+    /// Consumes the rate limiter, returning the input stream. Any pending item
+    /// is dropped.
+    pub fn into_inner(self) -> S {
+        self.input
+    }
+}
+
+impl<S: Stream> Stream for RateLimitedStream<S> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        let this = self.project();
+        let mut input = this.input;
+        this.limiter
+            .poll_next(cx, |cx| input.as_mut().poll_next(cx))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.limiter.size_hint(self.input.size_hint())
+    }
+}
+
+impl<T> Stream for RateLimitedReceiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+    use tokio::time::Instant;
+    use tokio_stream::wrappers::ReceiverStream;
+    use tokio_stream::StreamExt;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_yields_latest_item_per_delay() {
+        let (input_tx, input_rx) = mpsc::channel::<i32>(10);
+        let mut stream =
+            RateLimitedStream::new(ReceiverStream::new(input_rx), Duration::from_secs(1));
+
+        let start = Instant::now();
+        for value in [1, 2, 3] {
+            input_tx.send(value).await.unwrap();
+        }
+
+        assert_eq!(stream.next().await, Some(1));
+        assert_eq!(stream.next().await, Some(3));
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+
+        // After a quiet period the next item goes out straight away
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        input_tx.send(4).await.unwrap();
+        assert_eq!(stream.next().await, Some(4));
+        assert_eq!(start.elapsed(), Duration::from_secs(6));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_close_policy() {
+        let items = RateLimitedStream::new(tokio_stream::iter(1..=3), Duration::from_secs(1))
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(items, vec![1]);
+
+        let start = Instant::now();
+        let items = RateLimitedStream::with_close_policy(
+            tokio_stream::iter(1..=3),
+            Duration::from_secs(1),
+            ClosePolicy::EmitAfterDelay,
+        )
+        .collect::<Vec<_>>()
+        .await;
+        assert_eq!(items, vec![1, 3]);
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,1056 @@
+use std::collections::VecDeque;
+use std::iter;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{Receiver, Sender, UnboundedReceiver};
+use tokio::time::{sleep_until, Instant};
+
+use crate::discard::{Discard, DiscardReason};
+use crate::handle::{Command, Snapshot};
+use crate::pending::{Pending, Pushed};
+use crate::stats::Counters;
+use crate::{ClosePolicy, CompletionReason, EmissionMode, FirstValue};
+
+/// Settings the worker needs, taken from the builder.
+#[derive(Debug, Clone)]
+pub(crate) struct WorkerConfig {
+    pub(crate) delay: Duration,
+    pub(crate) mode: EmissionMode,
+    /// Never shorter than the delay, however the delay changes.
+    pub(crate) max_wait: Option<Duration>,
+    pub(crate) leading: bool,
+    pub(crate) trailing: bool,
+    pub(crate) close_policy: ClosePolicy,
+    pub(crate) first_value: FirstValue,
+}
+
+/// Values that have been taken from a [`Schedule`] and are waiting to be sent,
+/// in order.
+pub(crate) type Outbox<T> = VecDeque<T>;
+
+/// Decides when the values a worker receives are sent.
+///
+/// This is all that differs between a plain and a keyed channel:
+/// [`run_worker`] drives either one, and handles the commands from the
+/// [`RateLimiterHandle`](crate::RateLimiterHandle) and the close policy the
+/// same way for both.
+pub(crate) trait Schedule {
+    /// The type received on the input channel.
+    type Item;
+    /// The type sent on the output channel.
+    type Output;
+    /// The type of a pending value, as [`peek`](Self::peek) returns it.
+    type Value;
+
+    /// Returns `true` if input should stay in the input channel until
+    /// something has been sent.
+    fn is_full(&self) -> bool {
+        false
+    }
+
+    /// Stores a value received at `now`, counting and discarding any value it
+    /// displaces.
+    ///
+    /// Returns the value back if it can't be stored and the worker should stop.
+    fn receive(
+        &mut self,
+        value: Self::Item,
+        now: Instant,
+        discard: &Discard<Self::Item>,
+        counters: &Counters,
+    ) -> Result<(), Self::Item>;
+
+    /// Catches up with the time that has passed, before anything is sent at
+    /// `now`.
+    fn advance(&mut self, _now: Instant, _counters: &Counters) {}
+
+    /// Removes the next value if it may be sent at `now`, moving the window on
+    /// as if it was sent then.
+    fn take_due(&mut self, now: Instant) -> Option<Self::Output>;
+
+    /// When [`advance`](Self::advance) or [`take_due`](Self::take_due) next
+    /// has something to do. `can_send` is `false` while the worker is paused
+    /// or a value is waiting for room in the output channel.
+    fn wake_at(&self, can_send: bool) -> Option<Instant>;
+
+    /// Switches to `new_delay`, moving any wait in progress.
+    fn set_delay(&mut self, new_delay: Duration, now: Instant);
+
+    /// Moves every pending value to `outbox`. With `reset_window` each one
+    /// counts as sent at `now`; without it the windows keep their ends.
+    fn flush(&mut self, reset_window: bool, now: Instant, outbox: &mut Outbox<Self::Output>);
+
+    fn is_empty(&self) -> bool;
+
+    /// The earliest time the next value may be sent.
+    fn next_emit_at(&self) -> Instant;
+
+    /// The next value to send, without removing it.
+    fn peek(&self) -> Option<&Self::Value>;
+
+    /// The pending value a value that was taken for sending holds.
+    fn value(output: &Self::Output) -> &Self::Value;
+
+    /// Finishes a value that was taken, as it is sent.
+    fn sending(_output: &mut Self::Output) {}
+
+    /// Removes every pending value, as the values that were received.
+    fn drain(&mut self) -> Vec<Self::Item>;
+
+    /// Splits a value that was taken for sending back into the values that
+    /// were received.
+    fn items(output: Self::Output) -> Vec<Self::Item>;
+
+    /// Removes the next value to send while the worker stops at `now`, along
+    /// with when it would have been sent normally.
+    fn take_on_close(&mut self, now: Instant) -> Option<(Instant, Self::Output)>;
+}
+
+/// Worker function that processes the input channel and sends to the output channel
+/// at the rate `schedule` allows.
+///
+/// Values are moved from the input to the output; the worker never needs to
+/// copy them. A value that is due waits in the outbox until the output
+/// channel has room for it, and the worker keeps handling input and commands
+/// in the meantime.
+pub(crate) async fn run_worker<S: Schedule>(
+    mut input: Receiver<S::Item>,
+    output: Sender<S::Output>,
+    mut commands: UnboundedReceiver<Command<S::Value>>,
+    mut schedule: S,
+    close_policy: ClosePolicy,
+    discard: Discard<S::Item>,
+    counters: Arc<Counters>,
+) -> CompletionReason {
+    let mut outbox = Outbox::new();
+    // When the value at the front of the outbox started waiting for room
+    let mut waiting_since: Option<Instant> = None;
+    // While paused nothing is sent, but input is still stored as usual
+    let mut paused = false;
+    let mut last_sent_at: Option<Instant> = None;
+
+    loop {
+        let now = Instant::now();
+        schedule.advance(now, &counters);
+
+        // Nothing more is taken while a value waits for room, so newer input
+        // keeps being combined with what is pending
+        if outbox.is_empty() && !paused {
+            if let Some(value) = schedule.take_due(now) {
+                // Delay elapsed, send the pending value
+                outbox.push_back(value);
+            }
+        }
+        if !outbox.is_empty() {
+            waiting_since.get_or_insert(now);
+        }
+
+        let wake_at = schedule.wake_at(!paused && outbox.is_empty());
+
+        tokio::select! {
+            // Due values go out first while the output channel has room, so
+            // commands and input only get in ahead of them while it's full
+            biased;
+
+            permit = output.reserve(), if !outbox.is_empty() => match permit {
+                Ok(permit) => {
+                    let mut value = outbox.pop_front().expect("the outbox has a value");
+                    S::sending(&mut value);
+                    permit.send(value);
+
+                    let now = Instant::now();
+                    counters.sent(now - waiting_since.take().unwrap_or(now));
+                    last_sent_at = Some(now);
+                }
+                Err(_) => {
+                    let unsent = outbox.into_iter().flat_map(S::items);
+                    discard_unsent(unsent.chain(schedule.drain()), &discard, &counters);
+                    return CompletionReason::OutputClosed;
+                }
+            },
+            Some(command) = commands.recv() => match command {
+                Command::Shutdown => {
+                    close(&output, schedule, outbox, paused, close_policy, &discard, &counters).await;
+                    return CompletionReason::Shutdown;
+                }
+                Command::SetDelay(new_delay) => schedule.set_delay(new_delay, Instant::now()),
+                Command::Pause => paused = true,
+                Command::Resume => paused = false,
+                Command::Flush { reset_window } => {
+                    // Everything pending goes out, even while paused
+                    schedule.flush(reset_window, Instant::now(), &mut outbox);
+                }
+                Command::Inspect(inspect) => {
+                    // A value waiting for room is the next one out, and could
+                    // have gone already
+                    let snapshot = Snapshot {
+                        pending: !outbox.is_empty() || !schedule.is_empty(),
+                        next_emit_at: waiting_since.unwrap_or_else(|| schedule.next_emit_at()),
+                        since_last_emit: last_sent_at.map(|sent_at| sent_at.elapsed()),
+                        paused,
+                    };
+                    let next = outbox.front().map(S::value);
+                    inspect(snapshot, next.or_else(|| schedule.peek()));
+                }
+            },
+            // A full queue stops reading input, so producers wait for room
+            new_value = input.recv(), if !schedule.is_full() => match new_value {
+                Some(value) => {
+                    Counters::increment(&counters.received);
+
+                    let stored = schedule.receive(value, Instant::now(), &discard, &counters);
+                    if let Err(value) = stored {
+                        // The queue overflowed and its policy says to give up
+                        Counters::increment(&counters.expired);
+                        discard.discard(value, DiscardReason::Expired);
+                        close(&output, schedule, outbox, paused, close_policy, &discard, &counters).await;
+                        return CompletionReason::QueueOverflow;
+                    }
+                }
+                None => {
+                    close(&output, schedule, outbox, paused, close_policy, &discard, &counters).await;
+                    return CompletionReason::InputClosed;
+                }
+            },
+            _ = sleep_until(wake_at.unwrap_or(now)), if wake_at.is_some() => {
+                // Time's up, whatever is due is handled on the next loop iteration
+            }
+            _ = output.closed(), if outbox.is_empty() => {
+                discard_unsent(schedule.drain(), &discard, &counters);
+                return CompletionReason::OutputClosed;
+            }
+        }
+    }
+}
+
+/// Handles the values that are still pending when the worker stops, according
+/// to `close_policy`. Values that go out keep to the schedule's rate.
+///
+/// Values in `outbox`, and any others that are due unless the worker is
+/// `paused`, still go out when pending values are dropped, as long as the
+/// output channel has room for them.
+async fn close<S: Schedule>(
+    output: &Sender<S::Output>,
+    mut schedule: S,
+    mut outbox: Outbox<S::Output>,
+    paused: bool,
+    close_policy: ClosePolicy,
+    discard: &Discard<S::Item>,
+    counters: &Counters,
+) {
+    let now = Instant::now();
+    if !paused {
+        while let Some(value) = schedule.take_due(now) {
+            outbox.push_back(value);
+        }
+    }
+
+    if close_policy == ClosePolicy::DropPending {
+        let mut dropped = Vec::new();
+        let mut unsent = Vec::new();
+        for mut value in outbox {
+            S::sending(&mut value);
+            match output.try_send(value) {
+                Ok(()) => counters.sent(Duration::ZERO),
+                Err(TrySendError::Full(value)) => dropped.extend(S::items(value)),
+                Err(TrySendError::Closed(value)) => unsent.extend(S::items(value)),
+            }
+        }
+        dropped.extend(schedule.drain());
+        discard_unsent(unsent, discard, counters);
+        for value in dropped {
+            Counters::increment(&counters.dropped_at_close);
+            discard.discard(value, DiscardReason::DroppedOnClose);
+        }
+        return;
+    }
+
+    let due = outbox.into_iter().map(|value| (now, value));
+    let rest = std::iter::from_fn(|| schedule.take_on_close(Instant::now()));
+    let mut values = due.chain(rest);
+
+    while let Some((send_at, mut value)) = values.next() {
+        if close_policy == ClosePolicy::EmitAfterDelay {
+            sleep_until(send_at).await;
+        }
+
+        let started_at = Instant::now();
+        let Ok(permit) = output.reserve().await else {
+            let unsent = iter::once(value).chain(values.map(|(_, value)| value));
+            let mut unsent: Vec<_> = unsent.flat_map(S::items).collect();
+            unsent.extend(schedule.drain());
+            discard_unsent(unsent, discard, counters);
+            return;
+        };
+
+        S::sending(&mut value);
+        permit.send(value);
+        counters.sent(started_at.elapsed());
+    }
+}
+
+/// Discards the values that can't be sent because the output channel has
+/// closed.
+fn discard_unsent<T>(
+    unsent: impl IntoIterator<Item = T>,
+    discard: &Discard<T>,
+    counters: &Counters,
+) {
+    for value in unsent {
+        Counters::increment(&counters.dropped_at_close);
+        discard.discard(value, DiscardReason::OutputClosed);
+    }
+}
+
+/// Schedules a plain channel: every value is stored in `pending`, which decides
+/// how a new value is combined with the ones already waiting, and what is sent
+/// once the mode allows.
+pub(crate) struct Paced<P> {
+    pending: P,
+    delay: Duration,
+    mode: EmissionMode,
+    max_wait: Option<Duration>,
+    leading: bool,
+    trailing: bool,
+    /// The earliest time the next value may be sent. When throttling, a window
+    /// is open for as long as this is in the future.
+    ready_at: Instant,
+    delay_first_value: bool,
+    /// The latest a debounced value may be held back, once one is pending
+    max_wait_deadline: Option<Instant>,
+    /// Where the max wait is measured from: the first value of the current
+    /// burst, or the last emission if the burst went on after it
+    max_wait_from: Option<Instant>,
+    /// When the most recent debounced value arrived
+    quiet_since: Instant,
+    /// For a token bucket, when the bucket would be empty again if it were
+    /// full now and drained at the sustained rate. Tracking this single instant
+    /// (the generic cell rate algorithm) stands in for counting tokens.
+    bucket_empty_at: Instant,
+}
+
+impl<P: Pending> Paced<P> {
+    pub(crate) fn new(pending: P, config: &WorkerConfig) -> Self {
+        let now = Instant::now();
+
+        Paced {
+            pending,
+            delay: config.delay,
+            mode: config.mode,
+            max_wait: config.max_wait,
+            leading: config.leading,
+            trailing: config.trailing,
+            ready_at: now,
+            delay_first_value: config.first_value == FirstValue::Delayed,
+            max_wait_deadline: None,
+            max_wait_from: None,
+            quiet_since: now,
+            bucket_empty_at: now,
+        }
+    }
+
+    /// When the pending values may go out, which may be later than the rate
+    /// allows if they would rather wait for more input.
+    fn send_at(&self) -> Instant {
+        self.ready_at
+            .max(self.pending.hold_until().unwrap_or(self.ready_at))
+    }
+
+    /// Moves the window on after a value went out at `now`.
+    fn sent(&mut self, now: Instant) {
+        self.max_wait_from = Some(now);
+        self.ready_at = next_ready_at(
+            self.mode,
+            self.leading,
+            self.delay,
+            now,
+            self.pending.is_empty(),
+            &mut self.bucket_empty_at,
+        );
+    }
+}
+
+impl<P: Pending> Schedule for Paced<P> {
+    type Item = P::Item;
+    type Output = P::Output;
+    type Value = P::Value;
+
+    fn is_full(&self) -> bool {
+        self.pending.is_full()
+    }
+
+    fn receive(
+        &mut self,
+        value: P::Item,
+        now: Instant,
+        discard: &Discard<P::Item>,
+        counters: &Counters,
+    ) -> Result<(), P::Item> {
+        let pushed = match self.mode {
+            EmissionMode::Throttle => {
+                if now >= self.ready_at {
+                    if !self.leading || self.delay_first_value {
+                        // Open a window and hold the value back until it ends
+                        self.ready_at = now + self.delay;
+                    }
+
+                    self.delay_first_value = false;
+                    self.pending.push(value)?
+                } else if self.trailing || self.pending.is_lossless() {
+                    // Inside a window, keep the value for later
+                    self.pending.push(value)?
+                } else {
+                    // The window has no trailing edge, so the value is dropped
+                    Pushed::Rejected(value)
+                }
+            }
+            EmissionMode::Debounce => {
+                if self.pending.is_empty() {
+                    // A burst only ends once the input goes quiet for a full
+                    // delay; until then the max wait counts from the last
+                    // emission, as lodash's maxWait does
+                    let from = match self.max_wait_from {
+                        Some(from) if now < self.quiet_since + self.delay => from,
+                        _ => now,
+                    };
+                    self.max_wait_from = Some(from);
+                    self.max_wait_deadline = self
+                        .max_wait
+                        .map(|max_wait| from + max_wait.max(self.delay));
+                }
+
+                // Every new value restarts the quiet period, up to the max wait
+                self.quiet_since = now;
+                self.ready_at = now + self.delay;
+                if let Some(deadline) = self.max_wait_deadline {
+                    self.ready_at = self.ready_at.min(deadline);
+                }
+
+                self.pending.push(value)?
+            }
+            EmissionMode::TokenBucket { .. } => {
+                // Goes out as soon as a token is available
+                self.pending.push(value)?
+            }
+        };
+
+        counters.pushed(&pushed);
+        discard.pushed(pushed);
+        Ok(())
+    }
+
+    fn take_due(&mut self, now: Instant) -> Option<P::Output> {
+        if now < self.send_at() {
+            return None;
+        }
+
+        let value = self.pending.take()?;
+        self.sent(now);
+        Some(value)
+    }
+
+    fn wake_at(&self, can_send: bool) -> Option<Instant> {
+        (can_send && !self.pending.is_empty()).then(|| self.send_at())
+    }
+
+    fn set_delay(&mut self, new_delay: Duration, now: Instant) {
+        match self.mode {
+            EmissionMode::TokenBucket { burst } => {
+                // Tokens already spent are paid back at the new rate
+                let owed = self.bucket_empty_at.saturating_duration_since(now);
+                if !self.delay.is_zero() {
+                    self.bucket_empty_at =
+                        now + owed.mul_f64(new_delay.as_secs_f64() / self.delay.as_secs_f64());
+                }
+                self.ready_at = self
+                    .bucket_empty_at
+                    .checked_sub(new_delay * (burst - 1))
+                    .unwrap_or(now);
+            }
+            EmissionMode::Debounce if !self.pending.is_empty() => {
+                self.ready_at = self.quiet_since + new_delay;
+                if let Some(deadline) = self.max_wait_deadline {
+                    self.ready_at = self.ready_at.min(deadline);
+                }
+            }
+            _ if self.ready_at > now => {
+                // The wait in progress now ends the new delay after it began
+                self.ready_at = (self.ready_at + new_delay)
+                    .checked_sub(self.delay)
+                    .unwrap_or(now);
+            }
+            // A window that has already ended stays ended
+            _ => {}
+        }
+
+        self.delay = new_delay;
+    }
+
+    fn flush(&mut self, reset_window: bool, now: Instant, outbox: &mut Outbox<P::Output>) {
+        while let Some(value) = self.pending.take() {
+            outbox.push_back(value);
+
+            if reset_window {
+                self.sent(now);
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    fn next_emit_at(&self) -> Instant {
+        self.send_at()
+    }
+
+    fn peek(&self) -> Option<&P::Value> {
+        self.pending.peek()
+    }
+
+    fn value(output: &P::Output) -> &P::Value {
+        P::value(output)
+    }
+
+    fn sending(output: &mut P::Output) {
+        P::sending(output)
+    }
+
+    fn drain(&mut self) -> Vec<P::Item> {
+        self.pending.drain()
+    }
+
+    fn items(output: P::Output) -> Vec<P::Item> {
+        P::items(output)
+    }
+
+    /// The first value goes out when it would have normally, and any values
+    /// queued after it one per delay.
+    fn take_on_close(&mut self, now: Instant) -> Option<(Instant, P::Output)> {
+        let value = self.pending.take()?;
+        let send_at = self.ready_at;
+        self.ready_at = send_at.max(now) + self.delay;
+
+        Some((send_at, value))
+    }
+}
+
+/// When the next value may be sent after one went out at `now`.
+///
+/// `bucket_empty_at` is only used, and updated, for a token bucket.
+fn next_ready_at(
+    mode: EmissionMode,
+    leading: bool,
+    delay: Duration,
+    now: Instant,
+    pending_is_empty: bool,
+    bucket_empty_at: &mut Instant,
+) -> Instant {
+    match mode {
+        EmissionMode::TokenBucket { burst } => {
+            // Spend a token; the next one is available once the bucket
+            // holds at least one again
+            *bucket_empty_at = (*bucket_empty_at).max(now) + delay;
+            bucket_empty_at
+                .checked_sub(delay * (burst - 1))
+                .unwrap_or(now)
+        }
+        EmissionMode::Throttle if !leading && pending_is_empty => {
+            // Without a leading edge the next value opens a fresh window
+            now
+        }
+        _ => now + delay,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        ClosePolicy, CompletionReason, EmissionMode, OverflowPolicy, RateLimitedChannelBuilder,
+    };
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+    use tokio::time::{sleep, sleep_until, timeout_at, Instant};
+
+    /// Runs a throttled channel with a one second delay, sending values 0..=4
+    /// at 0, 200, 400, 1500 and 1600ms, and returns each emitted value with the
+    /// millisecond it arrived at.
+    async fn throttle_timeline(leading: bool, trailing: bool) -> Vec<(i32, u128)> {
+        const SCHEDULE: [u64; 5] = [0, 200, 400, 1500, 1600];
+
+        let (input_tx, input_rx) = mpsc::channel::<i32>(10);
+        let (mut output_rx, _handle) = RateLimitedChannelBuilder::new(Duration::from_secs(1))
+            .leading(leading)
+            .trailing(trailing)
+            .build(input_rx);
+
+        let start = Instant::now();
+        let producer_tx = input_tx.clone();
+        tokio::spawn(async move {
+            for (value, at) in SCHEDULE.into_iter().enumerate() {
+                sleep_until(start + Duration::from_millis(at)).await;
+                producer_tx.send(value as i32).await.unwrap();
+            }
+        });
+
+        let mut values = Vec::new();
+        let deadline = start + Duration::from_secs(5);
+        while let Ok(Some(value)) = timeout_at(deadline, output_rx.recv()).await {
+            values.push((value, start.elapsed().as_millis()));
+        }
+
+        values
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_throttle_leading_and_trailing() {
+        let values = throttle_timeline(true, true).await;
+
+        assert_eq!(values, vec![(0, 0), (2, 1000), (4, 2000)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_throttle_leading_only() {
+        let values = throttle_timeline(true, false).await;
+
+        assert_eq!(values, vec![(0, 0), (3, 1500)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_throttle_trailing_only() {
+        let values = throttle_timeline(false, true).await;
+
+        assert_eq!(values, vec![(2, 1000), (4, 2500)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_debounce_waits_for_quiet_period() {
+        const DELAY: Duration = Duration::from_secs(1);
+
+        let (input_tx, input_rx) = mpsc::channel::<i32>(10);
+        let (mut output_rx, _handle) = RateLimitedChannelBuilder::new(DELAY)
+            .mode(EmissionMode::Debounce)
+            .build(input_rx);
+
+        let start = Instant::now();
+        input_tx.send(1).await.unwrap();
+        sleep(Duration::from_millis(500)).await;
+        input_tx.send(2).await.unwrap();
+        sleep(Duration::from_millis(900)).await;
+        input_tx.send(3).await.unwrap();
+
+        assert_eq!(output_rx.recv().await, Some(3));
+        assert_eq!(start.elapsed(), Duration::from_millis(2400));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_debounce_emits_isolated_values() {
+        const DELAY: Duration = Duration::from_secs(1);
+
+        let (input_tx, input_rx) = mpsc::channel::<i32>(10);
+        let (mut output_rx, _handle) = RateLimitedChannelBuilder::new(DELAY)
+            .mode(EmissionMode::Debounce)
+            .build(input_rx);
+
+        let start = Instant::now();
+        input_tx.send(1).await.unwrap();
+        assert_eq!(output_rx.recv().await, Some(1));
+        assert_eq!(start.elapsed(), DELAY);
+
+        input_tx.send(2).await.unwrap();
+        assert_eq!(output_rx.recv().await, Some(2));
+        assert_eq!(start.elapsed(), DELAY * 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_debounce_max_wait_forces_emission() {
+        const DELAY: Duration = Duration::from_millis(300);
+        const MAX_WAIT: Duration = Duration::from_secs(1);
+
+        let (input_tx, input_rx) = mpsc::channel::<i32>(100);
+        let (mut output_rx, _handle) = RateLimitedChannelBuilder::new(DELAY)
+            .mode(EmissionMode::Debounce)
+            .max_wait(MAX_WAIT)
+            .build(input_rx);
+
+        let start = Instant::now();
+
+        // Never goes quiet for long enough to satisfy the delay
+        let producer_tx = input_tx.clone();
+        tokio::spawn(async move {
+            for i in 0..17 {
+                producer_tx.send(i).await.unwrap();
+                sleep(Duration::from_millis(150)).await;
+            }
+        });
+
+        // The burst is cut off one max wait after its first value, then one
+        // max wait after each emission
+        assert_eq!(output_rx.recv().await, Some(6));
+        assert_eq!(start.elapsed(), MAX_WAIT);
+        assert_eq!(output_rx.recv().await, Some(13));
+        assert_eq!(start.elapsed(), MAX_WAIT * 2);
+
+        // Input stops after 2.4s, so the last burst ends with a normal quiet period
+        assert_eq!(output_rx.recv().await, Some(16));
+        assert_eq!(start.elapsed(), Duration::from_millis(2700));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_debounce_max_wait_shorter_than_delay() {
+        const DELAY: Duration = Duration::from_secs(1);
+
+        let (input_tx, input_rx) = mpsc::channel::<i32>(10);
+        let (mut output_rx, _handle) = RateLimitedChannelBuilder::new(DELAY)
+            .mode(EmissionMode::Debounce)
+            .max_wait(Duration::from_millis(100))
+            .build(input_rx);
+
+        let start = Instant::now();
+        input_tx.send(1).await.unwrap();
+
+        assert_eq!(output_rx.recv().await, Some(1));
+        assert_eq!(start.elapsed(), DELAY);
+    }
+
+    /// Sends values 0..5 to a lossless channel with a one second delay all at
+    /// once and returns each emitted value with the millisecond it arrived at.
+    async fn lossless_timeline(capacity: usize, overflow: OverflowPolicy) -> Vec<(i32, u128)> {
+        let (input_tx, input_rx) = mpsc::channel::<i32>(10);
+        let (mut output_rx, _handle) = RateLimitedChannelBuilder::new(Duration::from_secs(1))
+            .lossless(capacity, overflow)
+            .build(input_rx);
+
+        let start = Instant::now();
+        for value in 0..5 {
+            input_tx.send(value).await.unwrap();
+        }
+
+        let mut values = Vec::new();
+        let deadline = start + Duration::from_secs(10);
+        while let Ok(Some(value)) = timeout_at(deadline, output_rx.recv()).await {
+            values.push((value, start.elapsed().as_millis()));
+        }
+
+        values
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lossless_delivers_every_value_in_order() {
+        let values = lossless_timeline(10, OverflowPolicy::Block).await;
+
+        assert_eq!(
+            values,
+            vec![(0, 0), (1, 1000), (2, 2000), (3, 3000), (4, 4000)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lossless_drop_oldest() {
+        let values = lossless_timeline(2, OverflowPolicy::DropOldest).await;
+
+        assert_eq!(values, vec![(0, 0), (3, 1000), (4, 2000)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lossless_drop_newest() {
+        let values = lossless_timeline(2, OverflowPolicy::DropNewest).await;
+
+        assert_eq!(values, vec![(0, 0), (1, 1000), (2, 2000)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lossless_block_holds_back_producer() {
+        let (input_tx, input_rx) = mpsc::channel::<i32>(1);
+        let (mut output_rx, _handle) = RateLimitedChannelBuilder::new(Duration::from_secs(1))
+            .lossless(1, OverflowPolicy::Block)
+            .build(input_rx);
+
+        let start = Instant::now();
+        let producer = tokio::spawn(async move {
+            for value in 0..5 {
+                input_tx.send(value).await.unwrap();
+            }
+
+            start.elapsed()
+        });
+
+        for expected in 0..5 {
+            assert_eq!(output_rx.recv().await, Some(expected));
+        }
+
+        // One value in the queue and one in the input channel, so the producer
+        // can only finish once the third value has gone out
+        assert_eq!(producer.await.unwrap(), Duration::from_secs(2));
+        assert_eq!(output_rx.recv().await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lossless_drains_queue_on_close() {
+        let (input_tx, input_rx) = mpsc::channel::<i32>(10);
+        let (mut output_rx, _handle) = RateLimitedChannelBuilder::new(Duration::from_secs(1))
+            .lossless(10, OverflowPolicy::Block)
+            .build(input_rx);
+
+        let start = Instant::now();
+        for value in 0..5 {
+            input_tx.send(value).await.unwrap();
+        }
+        drop(input_tx);
+
+        let mut values = Vec::new();
+        while let Some(value) = output_rx.recv().await {
+            values.push((value, start.elapsed().as_millis()));
+        }
+
+        assert_eq!(
+            values,
+            vec![(0, 0), (1, 1000), (2, 2000), (3, 3000), (4, 4000)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lossless_error_stops_worker() {
+        let (input_tx, input_rx) = mpsc::channel::<i32>(10);
+        let (mut output_rx, handle) = RateLimitedChannelBuilder::new(Duration::from_secs(1))
+            .lossless(1, OverflowPolicy::Error)
+            .close_policy(ClosePolicy::DropPending)
+            .build(input_rx);
+
+        for value in 0..3 {
+            input_tx.send(value).await.unwrap();
+        }
+
+        assert_eq!(output_rx.recv().await, Some(0));
+        assert_eq!(output_rx.recv().await, None);
+
+        // The value that didn't fit is counted as expired, and the queued one
+        // as dropped by the close policy
+        let stats = handle.stats();
+        assert_eq!((stats.expired, stats.dropped_at_close), (1, 1));
+        assert_eq!(
+            handle.join().await.unwrap(),
+            CompletionReason::QueueOverflow
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_token_bucket_lossless_burst() {
+        let (input_tx, input_rx) = mpsc::channel::<i32>(10);
+        let (mut output_rx, _handle) = RateLimitedChannelBuilder::new(Duration::from_secs(1))
+            .mode(EmissionMode::TokenBucket { burst: 3 })
+            .lossless(10, OverflowPolicy::Block)
+            .build(input_rx);
+
+        let start = Instant::now();
+        for value in 0..6 {
+            input_tx.send(value).await.unwrap();
+        }
+
+        let mut values = Vec::new();
+        for _ in 0..6 {
+            let value = output_rx.recv().await.unwrap();
+            values.push((value, start.elapsed().as_millis()));
+        }
+
+        // A full bucket lets three through, then one per second
+        assert_eq!(
+            values,
+            vec![(0, 0), (1, 0), (2, 0), (3, 1000), (4, 2000), (5, 3000)]
+        );
+
+        // After idling long enough the bucket refills completely
+        sleep_until(start + Duration::from_secs(13)).await;
+        for value in 6..9 {
+            input_tx.send(value).await.unwrap();
+        }
+        for expected in 6..9 {
+            assert_eq!(output_rx.recv().await, Some(expected));
+            assert_eq!(start.elapsed(), Duration::from_secs(13));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_token_bucket_latest_wins() {
+        const SCHEDULE: [u64; 4] = [0, 100, 200, 300];
+
+        let (input_tx, input_rx) = mpsc::channel::<i32>(10);
+        let (mut output_rx, _handle) = RateLimitedChannelBuilder::new(Duration::from_secs(1))
+            .mode(EmissionMode::TokenBucket { burst: 2 })
+            .build(input_rx);
+
+        let start = Instant::now();
+        let producer_tx = input_tx.clone();
+        tokio::spawn(async move {
+            for (value, at) in SCHEDULE.into_iter().enumerate() {
+                sleep_until(start + Duration::from_millis(at)).await;
+                producer_tx.send(value as i32).await.unwrap();
+            }
+        });
+
+        let mut values = Vec::new();
+        let deadline = start + Duration::from_secs(5);
+        while let Ok(Some(value)) = timeout_at(deadline, output_rx.recv()).await {
+            values.push((value, start.elapsed().as_millis()));
+        }
+
+        // Two tokens cover the first two values; the next token arrives one
+        // delay after the first was spent, by which time 3 has replaced 2
+        assert_eq!(values, vec![(0, 0), (1, 100), (3, 1000)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_batch_flushes_when_full_or_aged() {
+        const MIN_INTERVAL: Duration = Duration::from_millis(100);
+
+        let (input_tx, input_rx) = mpsc::channel::<i32>(10);
+        let (mut output_rx, _handle) = RateLimitedChannelBuilder::new(MIN_INTERVAL)
+            .max_batch_size(3)
+            .max_batch_delay(Duration::from_secs(1))
+            .build_batched(input_rx);
+
+        let start = Instant::now();
+        for value in 0..7 {
+            input_tx.send(value).await.unwrap();
+        }
+
+        let mut batches = Vec::new();
+        for _ in 0..3 {
+            let batch = output_rx.recv().await.unwrap();
+            batches.push((batch, start.elapsed().as_millis()));
+        }
+
+        // Full batches go out as often as the minimum interval allows, the
+        // last one waits for its maximum delay
+        assert_eq!(
+            batches,
+            vec![(vec![0, 1, 2], 0), (vec![3, 4, 5], 100), (vec![6], 1100)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_batch_flushes_by_weight() {
+        let (input_tx, input_rx) = mpsc::channel::<&str>(10);
+        let (mut output_rx, _handle) = RateLimitedChannelBuilder::new(Duration::ZERO)
+            .max_batch_delay(Duration::from_secs(1))
+            .build_batched_by_weight(input_rx, 10, |value| value.len());
+
+        let start = Instant::now();
+        for value in ["aaaa", "bbbb", "cc", "d"] {
+            input_tx.send(value).await.unwrap();
+        }
+
+        assert_eq!(output_rx.recv().await, Some(vec!["aaaa", "bbbb", "cc"]));
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        assert_eq!(output_rx.recv().await, Some(vec!["d"]));
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+    }
+
+    /// Sends two values to a throttled channel with a `delay` of one second,
+    /// changes the delay to `new_delay` after 500ms and returns when the second
+    /// value arrives.
+    async fn second_value_after_set_delay(new_delay: Duration) -> Duration {
+        let (input_tx, input_rx) = mpsc::channel::<i32>(10);
+        let (mut output_rx, handle) =
+            RateLimitedChannelBuilder::new(Duration::from_secs(1)).build(input_rx);
+
+        let start = Instant::now();
+        input_tx.send(1).await.unwrap();
+        input_tx.send(2).await.unwrap();
+        assert_eq!(output_rx.recv().await, Some(1));
+
+        sleep(Duration::from_millis(500)).await;
+        handle.set_delay(new_delay);
+
+        assert_eq!(output_rx.recv().await, Some(2));
+        start.elapsed()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_set_delay_shortens_wait() {
+        let elapsed = second_value_after_set_delay(Duration::from_millis(700)).await;
+        assert_eq!(elapsed, Duration::from_millis(700));
+
+        // Already past the end of the shorter window
+        let elapsed = second_value_after_set_delay(Duration::from_millis(100)).await;
+        assert_eq!(elapsed, Duration::from_millis(500));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_set_delay_extends_wait() {
+        let elapsed = second_value_after_set_delay(Duration::from_secs(3)).await;
+
+        assert_eq!(elapsed, Duration::from_secs(3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pause_coalesces_until_resume() {
+        let (input_tx, input_rx) = mpsc::channel::<i32>(10);
+        let (mut output_rx, handle) =
+            RateLimitedChannelBuilder::new(Duration::from_secs(1)).build(input_rx);
+
+        let start = Instant::now();
+        input_tx.send(1).await.unwrap();
+        assert_eq!(output_rx.recv().await, Some(1));
+
+        handle.pause();
+        input_tx.send(2).await.unwrap();
+        input_tx.send(3).await.unwrap();
+        let resume_at = start + Duration::from_secs(3);
+        assert!(timeout_at(resume_at, output_rx.recv()).await.is_err());
+
+        // The window ended while paused, so the latest value goes out at once
+        handle.resume();
+        assert_eq!(output_rx.recv().await, Some(3));
+        assert_eq!(start.elapsed(), Duration::from_secs(3));
+
+        // Resuming inside a window waits for it to end
+        handle.pause();
+        input_tx.send(4).await.unwrap();
+        sleep(Duration::from_millis(500)).await;
+        handle.resume();
+        assert_eq!(output_rx.recv().await, Some(4));
+        assert_eq!(start.elapsed(), Duration::from_secs(4));
+    }
+
+    /// Flushes the second of three values sent to a throttled channel with a
+    /// one second delay after 200ms, and returns when the second and third
+    /// values arrive.
+    async fn flush_timeline(reset_window: bool) -> (Duration, Duration) {
+        let (input_tx, input_rx) = mpsc::channel::<i32>(10);
+        let (mut output_rx, handle) =
+            RateLimitedChannelBuilder::new(Duration::from_secs(1)).build(input_rx);
+
+        let start = Instant::now();
+        input_tx.send(1).await.unwrap();
+        input_tx.send(2).await.unwrap();
+        assert_eq!(output_rx.recv().await, Some(1));
+
+        sleep(Duration::from_millis(200)).await;
+        handle.flush(reset_window);
+        assert_eq!(output_rx.recv().await, Some(2));
+        let flushed_at = start.elapsed();
+
+        input_tx.send(3).await.unwrap();
+        assert_eq!(output_rx.recv().await, Some(3));
+
+        (flushed_at, start.elapsed())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_flush_keeps_window() {
+        let (flushed_at, next_at) = flush_timeline(false).await;
+
+        assert_eq!(flushed_at, Duration::from_millis(200));
+        assert_eq!(next_at, Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_flush_resets_window() {
+        let (flushed_at, next_at) = flush_timeline(true).await;
+
+        assert_eq!(flushed_at, Duration::from_millis(200));
+        assert_eq!(next_at, Duration::from_millis(1200));
+    }
+}